@@ -0,0 +1,256 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use log::{error, info};
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often backends get pinged with Strategy::LowestPing
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How to pick the backend for a new connection. Other backends are tried if it is unreachable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Always prefer the first backend, use the next ones only while it is down
+    Failover,
+    /// Take turns
+    RoundRobin,
+    /// The one with the fewest forwarded connections
+    LeastConnections,
+    /// The one with the lowest ping (measured regularly)
+    LowestPing,
+}
+
+/// Host and optional port, as given on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendAddr {
+    pub host: String,
+    /// Without a port, the SRV record or default port gets used
+    pub port: Option<u16>,
+}
+
+impl BackendAddr {
+    /// Parse "HOST", "HOST:PORT", "IPV6" or "[IPV6]:PORT"
+    pub fn parse(addr: &str) -> Result<Self, String> {
+        if let Ok(addr) = addr.parse::<SocketAddr>() {
+            return Ok(Self {
+                host: addr.ip().to_string(),
+                port: Some(addr.port()),
+            });
+        } else if addr.parse::<IpAddr>().is_ok() {
+            return Ok(Self {
+                host: addr.to_owned(),
+                port: None,
+            });
+        }
+        match addr.rsplit_once(':') {
+            Some((host, port)) => Ok(Self {
+                host: host.to_owned(),
+                port: Some(
+                    port.parse()
+                        .map_err(|_| format!("Invalid port in \"{addr}\""))?,
+                ),
+            }),
+            None => Ok(Self {
+                host: addr.to_owned(),
+                port: None,
+            }),
+        }
+    }
+}
+
+impl Display for BackendAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{port}", self.host),
+            None => write!(f, "{}", self.host),
+        }
+    }
+}
+
+pub struct Backend {
+    pub addr: BackendAddr,
+    /// Forwarded connections (see BackendLease)
+    active: AtomicUsize,
+    /// Last measured ping in ms. u32::MAX if unknown or unreachable.
+    ping_ms: AtomicU32,
+}
+
+impl Backend {
+    pub fn record_ping(&self, ping_ms: Option<u32>) {
+        self.ping_ms
+            .store(ping_ms.unwrap_or(u32::MAX), Ordering::Relaxed);
+    }
+}
+
+/// Counts as a forwarded connection of the backend until dropped
+pub struct BackendLease(Arc<Backend>);
+
+impl BackendLease {
+    pub fn new(backend: Arc<Backend>) -> Self {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Self(backend)
+    }
+}
+
+impl Drop for BackendLease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Backends {
+    backends: Vec<Arc<Backend>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl Backends {
+    pub fn new(addrs: Vec<BackendAddr>, strategy: Strategy) -> Self {
+        let backends = addrs
+            .into_iter()
+            .map(|addr| {
+                Arc::new(Backend {
+                    addr,
+                    active: AtomicUsize::new(0),
+                    ping_ms: AtomicU32::new(u32::MAX),
+                })
+            })
+            .collect();
+        Self {
+            backends,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// All backends in the order they should be tried for a new connection
+    pub fn candidates(&self) -> Vec<Arc<Backend>> {
+        let mut candidates = self.backends.clone();
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                candidates.rotate_left(next % self.backends.len().max(1));
+            }
+            Strategy::LeastConnections => {
+                candidates.sort_by_key(|backend| backend.active.load(Ordering::Relaxed))
+            }
+            Strategy::LowestPing => {
+                candidates.sort_by_key(|backend| backend.ping_ms.load(Ordering::Relaxed))
+            }
+        }
+        candidates
+    }
+
+    /// Run the attempt with each candidate until one succeeds. Returns the last error if all fail.
+    pub fn try_each<T>(
+        &self,
+        mut attempt: impl FnMut(&Backend) -> Result<T>,
+    ) -> Result<(Arc<Backend>, T)> {
+        let candidates = self.candidates();
+        let mut last_error = None;
+        for backend in candidates {
+            match attempt(&backend) {
+                Ok(result) => return Ok((backend, result)),
+                Err(err) => {
+                    if self.backends.len() > 1 {
+                        error!("Backend {} unavailable: {err:#}", backend.addr);
+                    }
+                    // Sort it last until it answers a ping again
+                    backend.record_ping(None);
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(match last_error {
+            Some(err) if self.backends.len() > 1 => {
+                err.context(format!("All {} backends failed", self.backends.len()))
+            }
+            Some(err) => err,
+            None => anyhow!("No backend configured"),
+        })
+    }
+
+    /// Keep the pings up to date for Strategy::LowestPing
+    pub fn start_pinging(
+        self: &Arc<Self>,
+        ping: impl Fn(&BackendAddr) -> Result<u32> + Send + 'static,
+    ) -> Result<()> {
+        if self.strategy != Strategy::LowestPing {
+            return Ok(());
+        }
+        let backends = self.clone();
+        std::thread::Builder::new()
+            .name("backend-ping".to_owned())
+            .spawn(move || loop {
+                for backend in &backends.backends {
+                    let result = ping(&backend.addr);
+                    if let Err(err) = &result {
+                        info!("Failed to ping backend {}: {err:#}", backend.addr);
+                    }
+                    backend.record_ping(result.ok());
+                }
+                std::thread::sleep(PING_INTERVAL);
+            })
+            .context("Spawn backend ping thread")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn parse_backend_addrs() {
+        let addr = |host: &str, port| BackendAddr {
+            host: host.to_owned(),
+            port,
+        };
+        assert_eq!(
+            BackendAddr::parse("mc.example.org"),
+            Ok(addr("mc.example.org", None))
+        );
+        assert_eq!(
+            BackendAddr::parse("mc.example.org:25566"),
+            Ok(addr("mc.example.org", Some(25566)))
+        );
+        assert_eq!(BackendAddr::parse("::1"), Ok(addr("::1", None)));
+        assert_eq!(
+            BackendAddr::parse("[::1]:25566"),
+            Ok(addr("::1", Some(25566)))
+        );
+        assert!(BackendAddr::parse("mc.example.org:port").is_err());
+    }
+
+    #[test]
+    fn order_candidates() {
+        let addrs: Vec<BackendAddr> = ["a", "b", "c"]
+            .iter()
+            .map(|host| BackendAddr::parse(host).unwrap())
+            .collect();
+        let hosts = |backends: &Backends| -> String {
+            backends
+                .candidates()
+                .iter()
+                .map(|backend| backend.addr.host.clone())
+                .collect()
+        };
+
+        let round_robin = Backends::new(addrs.clone(), Strategy::RoundRobin);
+        assert_eq!(hosts(&round_robin), "abc");
+        assert_eq!(hosts(&round_robin), "bca");
+
+        let least_connections = Backends::new(addrs.clone(), Strategy::LeastConnections);
+        let _lease = BackendLease::new(least_connections.candidates()[0].clone());
+        assert_eq!(hosts(&least_connections), "bca");
+
+        let lowest_ping = Backends::new(addrs, Strategy::LowestPing);
+        lowest_ping.backends[2].record_ping(Some(5));
+        lowest_ping.backends[1].record_ping(Some(20));
+        assert_eq!(hosts(&lowest_ping), "cba");
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::net::TcpStream;
+
+/// Who is responsible for combining small writes into fewer TCP segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Coalescing {
+    /// Hold data back in the proxy (see delay and flush bytes)
+    Userspace,
+    /// Write immediately, but keep TCP_CORK set and only uncork every delay
+    Cork,
+    /// Write immediately, but limit unsent data in the kernel with TCP_NOTSENT_LOWAT,
+    /// so data piles up in the proxy while the kernel is still busy sending
+    NotsentLowat,
+}
+
+impl Coalescing {
+    /// Fail early if the strategy can't work on this platform
+    pub fn ensure_supported(self) -> Result<()> {
+        if self != Coalescing::Userspace && !cfg!(target_os = "linux") {
+            bail!("Coalescing strategy {self:?} is only supported on Linux!");
+        }
+        Ok(())
+    }
+
+    /// Prepare a socket which data gets forwarded to
+    pub fn apply(self, socket: &TcpStream, notsent_lowat: u32) -> Result<()> {
+        match self {
+            Coalescing::Userspace => {}
+            Coalescing::Cork => set_tcp_cork(socket, true)?,
+            Coalescing::NotsentLowat => set_tcp_notsent_lowat(socket, notsent_lowat)?,
+        }
+        Ok(())
+    }
+}
+
+/// Send everything that was held back by TCP_CORK and keep corking after that
+pub fn uncork(socket: &TcpStream) -> Result<()> {
+    set_tcp_cork(socket, false)?;
+    set_tcp_cork(socket, true)
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_cork(socket: &TcpStream, cork: bool) -> Result<()> {
+    Ok(socket2::SockRef::from(socket).set_tcp_cork(cork)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_tcp_cork(_socket: &TcpStream, _cork: bool) -> Result<()> {
+    bail!("TCP_CORK is only supported on Linux!")
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_notsent_lowat(socket: &TcpStream, bytes: u32) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let value = libc::c_int::try_from(bytes)?;
+    // SAFETY: The fd is valid for the lifetime of socket and value outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_NOTSENT_LOWAT,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_tcp_notsent_lowat(_socket: &TcpStream, _bytes: u32) -> Result<()> {
+    bail!("TCP_NOTSENT_LOWAT is only supported on Linux!")
+}
@@ -0,0 +1,204 @@
+use crate::timeouts::Timeout;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use log::info;
+use polling::{Event, Events, Poller};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+/// How long to wait for an attempt before racing it against the next address (RFC 8305)
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => AddressFamily::Ipv4,
+            SocketAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+}
+
+/// Order addresses like Happy Eyeballs does: Alternating between both families, starting with
+/// the preferred one (or the one the resolver returned first).
+pub fn interleave(addrs: &[SocketAddr], prefer: Option<AddressFamily>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let prefer = prefer.unwrap_or(AddressFamily::of(first));
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .partition(|addr| AddressFamily::of(addr) == prefer);
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+struct Attempt {
+    socket: Socket,
+    addr: SocketAddr,
+    deadline: Instant,
+}
+
+/// Connect to the first address that accepts the connection. A new attempt is started every
+/// ATTEMPT_DELAY (or right away once the previous one failed) while older attempts keep going,
+/// each one limited to `timeout`. Binds to the source ip (if any), which has to match the
+/// family of all addresses.
+pub fn connect_any(
+    addrs: &[SocketAddr],
+    source_ip: Option<IpAddr>,
+    timeout: Duration,
+) -> Result<TcpStream> {
+    let poller = Poller::new().context("Create poller")?;
+    let mut events = Events::new();
+    let mut attempts: Vec<Option<Attempt>> = Vec::with_capacity(addrs.len());
+    let mut next_attempt_at = Instant::now();
+    let mut last_error = None;
+
+    let result = loop {
+        let now = Instant::now();
+        let pending = attempts.iter().flatten().count();
+        if attempts.len() < addrs.len() && (pending == 0 || next_attempt_at <= now) {
+            let addr = addrs[attempts.len()];
+            next_attempt_at = now + ATTEMPT_DELAY;
+            match start_attempt(addr, source_ip) {
+                Ok(socket) => {
+                    // SAFETY: Every socket is deleted from the poller before being dropped
+                    unsafe { poller.add(&socket, Event::writable(attempts.len())) }?;
+                    attempts.push(Some(Attempt {
+                        socket,
+                        addr,
+                        deadline: now + timeout,
+                    }));
+                }
+                Err(err) => {
+                    info!("Connecting to {addr} failed: {err}");
+                    last_error = Some(err);
+                    attempts.push(None);
+                }
+            }
+            continue;
+        }
+
+        for slot in &mut attempts {
+            if slot.as_ref().is_some_and(|attempt| attempt.deadline <= now) {
+                let attempt = slot.take().expect("Checked above");
+                info!(
+                    "Connecting to {} timed out after {}s",
+                    attempt.addr,
+                    timeout.as_secs()
+                );
+                poller.delete(&attempt.socket)?;
+            }
+        }
+        if attempts.len() == addrs.len() && attempts.iter().all(Option::is_none) {
+            break Err(match last_error {
+                Some(err) => err.context(format!(
+                    "Failed to connect to any of {} target addresses",
+                    addrs.len()
+                )),
+                None if addrs.is_empty() => anyhow!("No target address to connect to"),
+                None => Timeout::Connect(timeout).into(),
+            });
+        }
+
+        let wake_at = attempts
+            .iter()
+            .flatten()
+            .map(|attempt| attempt.deadline)
+            .chain((attempts.len() < addrs.len()).then_some(next_attempt_at))
+            .min();
+        events.clear();
+        match poller.wait(
+            &mut events,
+            wake_at.map(|at| at.saturating_duration_since(now)),
+        ) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            result => result?,
+        };
+
+        let mut connected = None;
+        for event in events.iter() {
+            let Some(attempt) = attempts[event.key].take() else {
+                continue;
+            };
+            poller.delete(&attempt.socket)?;
+            match attempt.socket.take_error() {
+                Ok(None) if connected.is_none() => connected = Some(attempt),
+                Ok(None) => {} // Lost the race
+                Ok(Some(err)) | Err(err) => {
+                    info!("Connecting to {} failed: {err}", attempt.addr);
+                    last_error = Some(anyhow::Error::from(err));
+                }
+            }
+        }
+        if let Some(attempt) = connected {
+            break Ok(attempt);
+        }
+    };
+
+    for attempt in attempts.into_iter().flatten() {
+        poller.delete(&attempt.socket)?;
+    }
+    let attempt = result?;
+    attempt.socket.set_nonblocking(false)?;
+    Ok(attempt.socket.into())
+}
+
+/// Create a socket and start connecting without waiting for the result
+fn start_attempt(addr: SocketAddr, source_ip: Option<IpAddr>) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(source_ip) = source_ip {
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::new(source_ip, 0).into())?;
+    }
+    socket.set_nonblocking(true)?;
+    match socket.connect(&SockAddr::from(addr)) {
+        Ok(()) => Ok(socket),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(socket),
+        #[cfg(unix)]
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => Ok(socket),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn interleave_families() {
+        let v4 = |last: u8| SocketAddr::from(([10, 0, 0, last], 25565));
+        let v6 = |last: u16| SocketAddr::from(([0xfd00, 0, 0, 0, 0, 0, 0, last], 25565));
+        let addrs = [v4(1), v4(2), v4(3), v6(1), v6(2)];
+
+        assert_eq!(
+            interleave(&addrs, None),
+            vec![v4(1), v6(1), v4(2), v6(2), v4(3)]
+        );
+        assert_eq!(
+            interleave(&addrs, Some(AddressFamily::Ipv6)),
+            vec![v6(1), v4(1), v6(2), v4(2), v4(3)]
+        );
+        assert_eq!(
+            interleave(&addrs[..2], Some(AddressFamily::Ipv6)),
+            addrs[..2]
+        );
+        assert!(interleave(&[], None).is_empty());
+    }
+}
@@ -0,0 +1,383 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, SystemTime};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// How long to wait for the DNS server
+pub const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Used to match responses to queries (not meant to be unpredictable)
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Parse "IP" or "IP:Port" (port defaults to 53)
+pub fn parse_server(server: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    match server.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+        Err(_) => Err(format!("Expected IP or IP:Port, got \"{server}\"")),
+    }
+}
+
+/// First nameserver from /etc/resolv.conf
+pub fn system_server() -> Result<SocketAddr> {
+    let resolv_conf =
+        std::fs::read_to_string("/etc/resolv.conf").context("Read /etc/resolv.conf")?;
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|server| server.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .context("No nameserver in /etc/resolv.conf")
+}
+
+/// Look up SRV records, ordered by priority (and highest weight first). Empty if none exist.
+pub fn lookup_srv(server: SocketAddr, name: &str, timeout: Duration) -> Result<Vec<SrvRecord>> {
+    parse_srv(&query(server, name, TYPE_SRV, timeout)?)
+}
+
+fn parse_srv(response: &[u8]) -> Result<Vec<SrvRecord>> {
+    let mut records = Vec::new();
+    for answer in parse_answers(response, TYPE_SRV)? {
+        let mut pos = answer.rdata_start;
+        let rdata = response.get(pos..pos + 6).context("SRV record too short")?;
+        pos += 6;
+        let target = read_name(response, &mut pos)?;
+        // A target of "." means the service is explicitly not available
+        if target.is_empty() {
+            continue;
+        }
+        records.push(SrvRecord {
+            priority: u16::from_be_bytes([rdata[0], rdata[1]]),
+            weight: u16::from_be_bytes([rdata[2], rdata[3]]),
+            port: u16::from_be_bytes([rdata[4], rdata[5]]),
+            target,
+        });
+    }
+    records.sort_by_key(|record| (record.priority, std::cmp::Reverse(record.weight)));
+    Ok(records)
+}
+
+/// Look up the IPv6 and IPv4 addresses (in that order) of a host
+pub fn lookup_ips(server: SocketAddr, name: &str, timeout: Duration) -> Result<Vec<IpAddr>> {
+    let mut ips = Vec::new();
+    for qtype in [TYPE_AAAA, TYPE_A] {
+        let response = query(server, name, qtype, timeout)?;
+        for answer in parse_answers(&response, qtype)? {
+            let rdata = &response[answer.rdata_start..answer.rdata_start + answer.rdata_len];
+            let ip = match rdata.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(rdata)?),
+                16 => IpAddr::from(<[u8; 16]>::try_from(rdata)?),
+                len => bail!("Address record has unexpected length {len}"),
+            };
+            ips.push(ip);
+        }
+    }
+    Ok(ips)
+}
+
+/// Send a query via UDP (or TCP if the response got truncated) and return the raw response
+fn query(server: SocketAddr, name: &str, qtype: u16, timeout: Duration) -> Result<Vec<u8>> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ^ SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos() as u16;
+    let request = build_query(id, name, qtype)?;
+
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).context("Bind DNS socket")?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send(&request)?;
+    let mut buf = vec![0u8; 4096];
+    let response = loop {
+        let len = socket.recv(&mut buf).map_err(|err| match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => anyhow!(
+                "No DNS response from {server} within {}s",
+                timeout.as_secs()
+            ),
+            _ => anyhow::Error::from(err).context(format!("Receive DNS response from {server}")),
+        })?;
+        // Ignore late responses to earlier queries
+        if len >= 12 && buf[..2] == id.to_be_bytes() {
+            break &buf[..len];
+        }
+    };
+    let truncated = response[2] & 0x02 != 0;
+    if !truncated {
+        return Ok(response.to_vec());
+    }
+
+    let mut stream = TcpStream::connect_timeout(&server, timeout)
+        .with_context(|| format!("Connect to DNS server {server} via TCP"))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&request);
+    stream.write_all(&framed)?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    if response.len() < 12 || response[..2] != id.to_be_bytes() {
+        bail!("Got unexpected DNS response via TCP");
+    }
+    Ok(response)
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut request = Vec::with_capacity(18 + name.len());
+    request.extend_from_slice(&id.to_be_bytes());
+    request.extend_from_slice(&[0x01, 0x00]); // Recursion desired
+    request.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // 1 question
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("Invalid domain name \"{name}\"");
+        }
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+    request.push(0);
+    request.extend_from_slice(&qtype.to_be_bytes());
+    request.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(request)
+}
+
+struct Answer {
+    rdata_start: usize,
+    rdata_len: usize,
+}
+
+/// Find all answers of the given type. A non-existing name results in no answers.
+fn parse_answers(response: &[u8], qtype: u16) -> Result<Vec<Answer>> {
+    if response.len() < 12 {
+        bail!("DNS response too short");
+    }
+    let rcode = response[3] & 0x0f;
+    if rcode == RCODE_NXDOMAIN {
+        return Ok(Vec::new());
+    } else if rcode != 0 {
+        bail!("DNS server responded with error code {rcode}");
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        read_name(response, &mut pos)?;
+        pos += 4; // Type and class
+    }
+    let mut found = Vec::new();
+    for _ in 0..answers {
+        read_name(response, &mut pos)?;
+        let header = response
+            .get(pos..pos + 10)
+            .context("DNS answer too short")?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdata_len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        pos += 10;
+        if pos + rdata_len > response.len() {
+            bail!("DNS answer too short");
+        }
+        // CNAMEs are already followed by the recursive resolver
+        if rtype == qtype {
+            found.push(Answer {
+                rdata_start: pos,
+                rdata_len,
+            });
+        }
+        pos += rdata_len;
+    }
+    Ok(found)
+}
+
+/// Read a (possibly compressed) name and advance pos past it
+fn read_name(message: &[u8], pos: &mut usize) -> Result<String> {
+    let mut labels = Vec::new();
+    let mut cursor = *pos;
+    let mut jumped = false;
+    for _ in 0..128 {
+        let len = *message.get(cursor).context("DNS name out of bounds")? as usize;
+        if len & 0xc0 == 0xc0 {
+            let low = *message.get(cursor + 1).context("DNS name out of bounds")? as usize;
+            if !jumped {
+                *pos = cursor + 2;
+                jumped = true;
+            }
+            cursor = ((len & 0x3f) << 8) | low;
+        } else if len == 0 {
+            if !jumped {
+                *pos = cursor + 1;
+            }
+            return Ok(labels.join("."));
+        } else {
+            let label = message
+                .get(cursor + 1..cursor + 1 + len)
+                .context("DNS name out of bounds")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            cursor += 1 + len;
+        }
+    }
+    bail!("DNS name has too many labels or pointers")
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn parse_compressed_srv_response() {
+        let mut response = build_query(0x1234, "_minecraft._tcp.example.org", TYPE_SRV).unwrap();
+        response[2] |= 0x80; // Response
+        response[7] = 2; // 2 answers
+        for (priority, weight, port, target) in [
+            (20u16, 0u16, 25566u16, &b"\x06backup\xc0\x1c"[..]),
+            (10, 5, 25565, &b"\x02mc\xc0\x1c"[..]),
+        ] {
+            response.extend_from_slice(&[0xc0, 12]); // Name of the question
+            response.extend_from_slice(&TYPE_SRV.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&300u32.to_be_bytes());
+            response.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            response.extend_from_slice(&priority.to_be_bytes());
+            response.extend_from_slice(&weight.to_be_bytes());
+            response.extend_from_slice(&port.to_be_bytes());
+            response.extend_from_slice(target);
+        }
+
+        let records = parse_srv(&response).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].target, "mc.example.org");
+        assert_eq!(records[0].port, 25565);
+        assert_eq!(records[1].target, "backup.example.org");
+
+        response[3] = RCODE_NXDOMAIN;
+        assert!(parse_srv(&response).unwrap().is_empty());
+    }
+
+    /// Answer record for the name of the question
+    fn record(rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut record = vec![0xc0, 12];
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&300u32.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    /// A or AAAA records (depending on the queried type) for the matching addresses
+    pub fn address_records(qtype: u16, ips: &[IpAddr]) -> Vec<Vec<u8>> {
+        ips.iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V4(ip) if qtype == TYPE_A => Some(record(TYPE_A, &ip.octets())),
+                IpAddr::V6(ip) if qtype == TYPE_AAAA => Some(record(TYPE_AAAA, &ip.octets())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// DNS server on 127.0.0.1 which answers from the records of the name and type (and with
+    /// NXDOMAIN for unknown names)
+    pub fn stand_in_server(records: fn(&str, u16) -> Option<Vec<Vec<u8>>>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                let mut response = buf[..len].to_vec();
+                let mut pos = 12;
+                let name = read_name(&response, &mut pos).unwrap();
+                let qtype = u16::from_be_bytes([response[pos], response[pos + 1]]);
+                response[2] |= 0x80; // Response
+                match records(&name, qtype) {
+                    Some(records) => {
+                        response[7] = records.len() as u8;
+                        records.iter().for_each(|record| response.extend(record));
+                    }
+                    None => response[3] = RCODE_NXDOMAIN,
+                }
+                socket.send_to(&response, peer).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn lookup_via_stand_in_server() {
+        let server = stand_in_server(|name, qtype| match (name, qtype) {
+            ("_minecraft._tcp.example.org", TYPE_SRV) => {
+                let mut rdata = vec![0, 10, 0, 5, 0x63, 0xde]; // Port 25566
+                rdata.extend_from_slice(b"\x02mc\x07example\x03org\x00");
+                Some(vec![record(TYPE_SRV, &rdata)])
+            }
+            ("mc.example.org", qtype) => Some(address_records(
+                qtype,
+                &[
+                    "10.0.0.1".parse().unwrap(),
+                    "2001:db8::1".parse().unwrap(),
+                    "10.0.0.2".parse().unwrap(),
+                ],
+            )),
+            ("example.org", _) => Some(Vec::new()),
+            _ => None,
+        });
+
+        assert_eq!(
+            lookup_srv(server, "_minecraft._tcp.example.org", TIMEOUT).unwrap(),
+            [SrvRecord {
+                priority: 10,
+                weight: 5,
+                port: 25566,
+                target: "mc.example.org".to_owned(),
+            }]
+        );
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            lookup_ips(server, "mc.example.org", TIMEOUT).unwrap(),
+            [ip("2001:db8::1"), ip("10.0.0.1"), ip("10.0.0.2")]
+        );
+        assert!(
+            lookup_srv(server, "_minecraft._tcp.missing.example.org", TIMEOUT)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(lookup_ips(server, "example.org", TIMEOUT)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn time_out_without_answer() {
+        // Receives the queries, but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = silent.local_addr().unwrap();
+        let started = std::time::Instant::now();
+        let err = lookup_ips(server, "mc.example.org", Duration::from_secs(1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("No DNS response from {server} within 1s")
+        );
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+}
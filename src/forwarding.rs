@@ -0,0 +1,154 @@
+use crate::protocol::types::{MinecraftDataType, VarInt, UUID};
+use crate::server_address::ServerAddress;
+use anyhow::Result;
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::net::IpAddr;
+
+/// Channel of the login plugin request which Velocity modern forwarding answers
+pub const VELOCITY_CHANNEL: &str = "velocity:player_info";
+
+/// Forwarding version without the chat signing key (accepted by all backends)
+const VELOCITY_MODERN_DEFAULT: i32 = 1;
+
+/// How the backend learns the real address of the player
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Forwarding {
+    /// The backend only sees the address of the proxy
+    None,
+    /// BungeeCord legacy forwarding: "host\0ip\0uuid" as server address in the handshake (needs "bungeecord: true" in spigot.yml)
+    Bungeecord,
+    /// Velocity modern forwarding: Answers the "velocity:player_info" login plugin request of the backend with the signed player info (needs the forwarding secret)
+    Velocity,
+}
+
+impl Forwarding {
+    /// Server address for the handshake to the backend, with the host of the client replaced
+    pub fn handshake_address(
+        self,
+        address: &ServerAddress,
+        host: &str,
+        strip_suffix: bool,
+        client_ip: IpAddr,
+        uuid: UUID,
+    ) -> String {
+        match self {
+            Forwarding::None | Forwarding::Velocity => address.with_host(host, strip_suffix),
+            // Spigot expects nothing but the properties after the uuid, so the suffix parts of the
+            // client are dropped (like BungeeCord does with ip forwarding)
+            Forwarding::Bungeecord => bungeecord_host(host, client_ip, uuid),
+        }
+    }
+}
+
+/// Host for the handshake in BungeeCord legacy format
+pub fn bungeecord_host(host: &str, client_ip: IpAddr, uuid: UUID) -> String {
+    format!("{host}\0{}\0{}", client_ip.to_canonical(), uuid.simple())
+}
+
+/// Shared secret of the proxy and backends for Velocity modern forwarding
+#[derive(Clone)]
+pub struct ForwardingSecret(pub Vec<u8>);
+
+impl ForwardingSecret {
+    /// Read from a file, like the forwarding.secret of Velocity (surrounding whitespace is ignored)
+    pub fn read(path: &str) -> Result<Self, String> {
+        let secret = std::fs::read_to_string(path).map_err(|err| format!("Read {path}: {err}"))?;
+        match secret.trim() {
+            "" => Err(format!("{path} is empty")),
+            secret => Ok(Self(secret.as_bytes().to_vec())),
+        }
+    }
+}
+
+/// Data of the response to the Velocity login plugin request: HMAC-SHA256 signature followed by
+/// the player info
+pub fn velocity_player_info(
+    secret: &[u8],
+    client_ip: IpAddr,
+    uuid: UUID,
+    username: &str,
+) -> Result<Vec<u8>> {
+    let mut info = Vec::new();
+    VarInt(VELOCITY_MODERN_DEFAULT).write_as_mc_type(&mut info)?;
+    client_ip
+        .to_canonical()
+        .to_string()
+        .write_as_mc_type(&mut info)?;
+    uuid.write_as_mc_type(&mut info)?;
+    username.to_owned().write_as_mc_type(&mut info)?;
+    // No properties (like the skin), as the proxy does not authenticate players
+    VarInt(0).write_as_mc_type(&mut info)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)?;
+    mac.update(&info);
+    let mut data = mac.finalize().into_bytes().to_vec();
+    data.extend_from_slice(&info);
+    Ok(data)
+}
+
+/// UUID the server uses for a player in offline mode (like Java's UUID.nameUUIDFromBytes)
+pub fn offline_uuid(username: &str) -> UUID {
+    let hash = Md5::digest(format!("OfflinePlayer:{username}"));
+    uuid::Builder::from_md5_bytes(hash.into()).into_uuid()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn bungeecord_handshake_host() {
+        assert_eq!(
+            offline_uuid("Notch").to_string(),
+            "b50ad385-829d-3141-a216-7e7d7539ba7f"
+        );
+        let ip: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+        assert_eq!(
+            bungeecord_host("mc.internal", ip, offline_uuid("Notch")),
+            "mc.internal\x00203.0.113.7\x00b50ad385829d3141a2167e7d7539ba7f"
+        );
+
+        let address = ServerAddress::parse("play.example.org\0FML3\0");
+        assert_eq!(
+            Forwarding::Bungeecord.handshake_address(
+                &address,
+                "mc.internal",
+                false,
+                ip,
+                offline_uuid("Notch")
+            ),
+            "mc.internal\x00203.0.113.7\x00b50ad385829d3141a2167e7d7539ba7f"
+        );
+        assert_eq!(
+            Forwarding::None.handshake_address(
+                &address,
+                "mc.internal",
+                false,
+                ip,
+                offline_uuid("Notch")
+            ),
+            "mc.internal\0FML3\0"
+        );
+    }
+
+    #[test]
+    fn signed_velocity_player_info() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let data = velocity_player_info(b"secret", ip, offline_uuid("Notch"), "Notch").unwrap();
+        let (signature, info) = data.split_at(32);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(info);
+        mac.verify_slice(signature).unwrap();
+
+        let mut expected = vec![1, 11];
+        expected.extend_from_slice(b"203.0.113.7");
+        expected.extend_from_slice(offline_uuid("Notch").as_bytes());
+        expected.push(5);
+        expected.extend_from_slice(b"Notch");
+        expected.push(0);
+        assert_eq!(info, expected);
+    }
+}
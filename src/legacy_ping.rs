@@ -0,0 +1,249 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use std::io::Read;
+
+/// First byte of a legacy server list ping
+const PING: u8 = 0xFE;
+/// Id of the kick packet, which carries the answer
+const KICK: u8 = 0xFF;
+/// Id of the plugin message packet, which 1.6 clients send along
+const PLUGIN_MESSAGE: u8 = 0xFA;
+
+/// Server list ping of a client before 1.7 (or a monitoring tool)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LegacyPing {
+    /// Beta 1.8 to 1.3 only send 0xFE and expect "MOTD§ONLINE§MAX"
+    Beta,
+    /// 1.4 and 1.5 send 0xFE 0x01 and expect the "§1" answer
+    V1_4,
+    /// 1.6 also sends the "MC|PingHost" plugin message
+    V1_6 {
+        protocol_version: u8,
+        host: String,
+        port: u16,
+    },
+}
+
+impl LegacyPing {
+    /// Whether the first (peeked) bytes of a connection are a legacy ping. A handshake can also
+    /// start with 0xFE (as part of its length), but its packet id 0x00 follows then.
+    pub fn detect(start: &[u8]) -> bool {
+        start.first() == Some(&PING) && start.get(2) != Some(&0x00)
+    }
+
+    /// Read the ping, `start` being the bytes that were peeked
+    pub fn read(reader: &mut impl Read, start: &[u8]) -> Result<Self> {
+        let mut ping = vec![0u8; start.len().min(2)];
+        reader.read_exact(&mut ping)?;
+        match ping[..] {
+            [PING] => return Ok(Self::Beta),
+            [PING, 0x01] if start.len() == 2 => return Ok(Self::V1_4),
+            [PING, 0x01] => {}
+            _ => bail!("Expected legacy ping"),
+        }
+
+        let mut id = [0u8];
+        reader.read_exact(&mut id)?;
+        if id[0] != PLUGIN_MESSAGE || read_string(reader)? != "MC|PingHost" {
+            bail!("Expected MC|PingHost after legacy ping");
+        }
+        let mut data = vec![0u8; read_u16(reader)? as usize];
+        reader.read_exact(&mut data).context("Read MC|PingHost")?;
+        let mut data = data.as_slice();
+        let mut protocol_version = [0u8];
+        data.read_exact(&mut protocol_version)?;
+        let host = read_string(&mut data)?;
+        let mut port = [0u8; 4];
+        data.read_exact(&mut port)?;
+        Ok(Self::V1_6 {
+            protocol_version: protocol_version[0],
+            host,
+            port: i32::from_be_bytes(port) as u16,
+        })
+    }
+
+    /// Kick packet with the MOTD, players and version from a (modern) status response
+    pub fn response(&self, status: &Value) -> Vec<u8> {
+        let motd = legacy_text(&status["description"]);
+        let online = status["players"]["online"].as_i64().unwrap_or_default();
+        let max = status["players"]["max"].as_i64().unwrap_or_default();
+        let text = match self {
+            // Formatting codes are not supported and "§" separates the fields
+            LegacyPing::Beta => format!("{}§{online}§{max}", strip_formatting(&motd)),
+            LegacyPing::V1_4 | LegacyPing::V1_6 { .. } => format!(
+                "§1\0{}\0{}\0{motd}\0{online}\0{max}",
+                status["version"]["protocol"].as_i64().unwrap_or(-1),
+                status["version"]["name"].as_str().unwrap_or_default(),
+            ),
+        };
+        let mut packet = vec![KICK];
+        write_string(&mut packet, &text);
+        packet
+    }
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+/// UTF-16 string with its length in code units in front
+fn read_string(reader: &mut impl Read) -> Result<String> {
+    let len = read_u16(reader)? as usize;
+    let mut bytes = vec![0u8; len * 2];
+    reader.read_exact(&mut bytes)?;
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+        .collect();
+    Ok(String::from_utf16(&units)?)
+}
+
+fn write_string(buf: &mut Vec<u8>, text: &str) {
+    let units: Vec<u16> = text.encode_utf16().take(u16::MAX as usize).collect();
+    buf.extend_from_slice(&(units.len() as u16).to_be_bytes());
+    for unit in units {
+        buf.extend_from_slice(&unit.to_be_bytes());
+    }
+}
+
+/// Text of a chat component, with its colors and styles as "§" formatting codes
+fn legacy_text(component: &Value) -> String {
+    let mut text = String::new();
+    append_legacy_text(component, &mut text);
+    text
+}
+
+fn append_legacy_text(component: &Value, text: &mut String) {
+    match component {
+        Value::String(part) => text.push_str(part),
+        Value::Array(parts) => parts.iter().for_each(|part| append_legacy_text(part, text)),
+        Value::Object(component) => {
+            append_formatting(component, text);
+            if let Some(Value::String(part)) = component.get("text") {
+                text.push_str(part);
+            }
+            if let Some(extra) = component.get("extra") {
+                append_legacy_text(extra, text);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn append_formatting(component: &Map<String, Value>, text: &mut String) {
+    const COLORS: [&str; 16] = [
+        "black",
+        "dark_blue",
+        "dark_green",
+        "dark_aqua",
+        "dark_red",
+        "dark_purple",
+        "gold",
+        "gray",
+        "dark_gray",
+        "blue",
+        "green",
+        "aqua",
+        "red",
+        "light_purple",
+        "yellow",
+        "white",
+    ];
+    let color = component.get("color").and_then(Value::as_str);
+    if let Some(index) = COLORS.iter().position(|name| Some(*name) == color) {
+        text.push('§');
+        text.push(char::from_digit(index as u32, 16).expect("Below 16"));
+    }
+    for (style, code) in [
+        ("obfuscated", 'k'),
+        ("bold", 'l'),
+        ("strikethrough", 'm'),
+        ("underlined", 'n'),
+        ("italic", 'o'),
+    ] {
+        if component.get(style) == Some(&Value::Bool(true)) {
+            text.push('§');
+            text.push(code);
+        }
+    }
+}
+
+/// Without "§" and the formatting code after it
+fn strip_formatting(text: &str) -> String {
+    let mut chars = text.chars();
+    let mut stripped = String::new();
+    while let Some(char) = chars.next() {
+        if char == '§' {
+            chars.next();
+        } else {
+            stripped.push(char);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn read_pings() {
+        let mut v1_6 = vec![PING, 0x01, PLUGIN_MESSAGE];
+        write_string(&mut v1_6, "MC|PingHost");
+        let mut data = vec![78];
+        write_string(&mut data, "play.example.org");
+        data.extend_from_slice(&25565i32.to_be_bytes());
+        v1_6.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        v1_6.extend_from_slice(&data);
+        assert!(LegacyPing::detect(&v1_6[..3]));
+        assert_eq!(
+            LegacyPing::read(&mut v1_6.as_slice(), &v1_6[..3]).unwrap(),
+            LegacyPing::V1_6 {
+                protocol_version: 78,
+                host: "play.example.org".to_owned(),
+                port: 25565,
+            }
+        );
+
+        let v1_4 = [PING, 0x01];
+        assert!(LegacyPing::detect(&v1_4));
+        assert_eq!(
+            LegacyPing::read(&mut &v1_4[..], &v1_4).unwrap(),
+            LegacyPing::V1_4
+        );
+        assert!(LegacyPing::detect(&[PING]));
+        assert_eq!(
+            LegacyPing::read(&mut &[PING][..], &[PING]).unwrap(),
+            LegacyPing::Beta
+        );
+
+        // Handshake with a length of 254 bytes
+        assert!(!LegacyPing::detect(&[PING, 0x01, 0x00]));
+        assert!(!LegacyPing::detect(&[0x10, 0x00, 0xff]));
+    }
+
+    #[test]
+    fn respond_with_status() {
+        let status = serde_json::json!({
+            "version": { "name": "1.21", "protocol": 767 },
+            "players": { "max": 20, "online": 3 },
+            "description": {
+                "text": "",
+                "extra": [{ "text": "Hello", "color": "gold", "bold": true }, " §8[§37ms§8]"],
+            },
+        });
+        let response = LegacyPing::V1_4.response(&status);
+        assert_eq!(response[0], KICK);
+        assert_eq!(
+            read_string(&mut &response[1..]).unwrap(),
+            "§1\x00767\x001.21\x00§6§lHello §8[§37ms§8]\x003\x0020"
+        );
+        let response = LegacyPing::Beta.response(&status);
+        assert_eq!(
+            read_string(&mut &response[1..]).unwrap(),
+            "Hello [7ms]§3§20"
+        );
+    }
+}
@@ -1,34 +1,92 @@
+use crate::backends::{Backend, BackendAddr, BackendLease, Backends, Strategy};
+use crate::coalescing::Coalescing;
+use crate::connect::AddressFamily;
+use crate::forwarding::{Forwarding, ForwardingSecret};
+use crate::legacy_ping::LegacyPing;
+use crate::metrics::{FailureKind, METRICS};
 use crate::protocol::client::handshake::ClientHandshake;
-use crate::protocol::client::login::{ClientLoginStart, ClientLoginStartOnlyName};
+use crate::protocol::client::login::{
+    ClientLoginPluginResponse, ClientLoginStart, ClientLoginStartOnlyName,
+};
 use crate::protocol::client::status::{ClientStatusPing, ClientStatusRequest};
-use crate::protocol::server::login::ServerLoginDisconnect;
+use crate::protocol::server::login::{ServerLoginDisconnect, ServerLoginPluginRequest};
 use crate::protocol::server::status::{ServerStatusPongPacket, ServerStatusResponsePacket};
 use crate::protocol::types::{MinecraftDataType, VarInt};
 use crate::protocol::Packet;
+use crate::proxy_protocol::{Cidr, ProxyProtocolVersion};
+use crate::reactor::{BurstTrigger, Connection, FlushPolicy, Reactor, ReactorConfig};
+use crate::resolver::Resolver;
+use crate::routing::{ProtocolRange, Route, RouteSpec, Router, LEGACY_PROTOCOL_VERSION};
+use crate::server_address::ServerAddress;
+use crate::stats::ConnectionStats;
+use crate::tcpshield::TcpShieldAddress;
+use crate::timeouts::{DeadlineStream, Timeout};
 use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
 use log::{error, info};
-use polling::{Event, Events, Poller};
 use serde_json::Value;
-use socket2::{Domain, Protocol, Socket, Type};
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream};
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, LazyLock, Mutex};
 use std::time::{Duration, Instant};
 use tracing::span::EnteredSpan;
 use tracing::{span, Level};
 use tracing_subscriber::prelude::*;
 
+mod backends;
+mod coalescing;
+mod connect;
+mod dns;
+mod forwarding;
+mod legacy_ping;
+mod metrics;
 mod protocol;
+mod proxy_protocol;
+mod reactor;
+mod resolver;
+mod routing;
+mod server_address;
+mod splice;
+mod stats;
+mod tcpshield;
+mod timeouts;
 
 #[derive(Parser)]
 struct Opts {
     /// Which host to connect clients to
     target_host: String,
 
-    /// Connect to a different port than the default one
-    #[clap(short = 'p', long = "port", default_value = "25565")]
-    target_port: u16,
+    /// Connect to a different port than the default one (25565). Without it, the _minecraft._tcp SRV record of the target host is used if there is one.
+    #[clap(short = 'p', long = "port")]
+    target_port: Option<u16>,
+
+    /// Further backends (HOST or HOST:PORT) with the same server, used depending on the balance strategy
+    #[clap(long = "backend", value_parser = BackendAddr::parse)]
+    backends: Vec<BackendAddr>,
+
+    /// How to choose between the target host and further backends. Unreachable ones are skipped.
+    #[clap(long, value_enum, default_value = "failover")]
+    balance: Strategy,
+
+    /// Forward clients which connect via a hostname (exact, *.wildcard or * for any) elsewhere: "PATTERN=BACKEND[,BACKEND...][;KEY=VALUE...]" with the optional keys protocol (versions like 47-340), balance, alias-host, alias-port, motd and delay. Other clients use the target host.
+    #[clap(long = "route", value_parser = RouteSpec::parse)]
+    routes: Vec<RouteSpec>,
+
+    /// Only allow clients with these protocol versions (e.g. 47-767, 764- or -340). Others see the unsupported version message.
+    #[clap(long, value_parser = ProtocolRange::parse)]
+    protocol_versions: Option<ProtocolRange>,
+
+    /// Shown in the server list and as kick reason to clients with a protocol version outside of protocol_versions
+    #[clap(
+        long,
+        default_value = "Your Minecraft version is not supported by this server"
+    )]
+    unsupported_version_message: String,
+
+    /// Appended to the MOTD of the target ("{ping}" is replaced with the own ping in ms)
+    #[clap(long, default_value = "§8[§9Stupid MC Proxy: §3{ping}ms§8]")]
+    motd_suffix: String,
 
     /// The IP:Port combo the server is listening on
     #[clap(short, long, default_value = "[::]:25565")]
@@ -42,19 +100,139 @@ struct Opts {
     #[clap(short = 'P', long)]
     alias_port: Option<u16>,
 
+    /// Drop what clients append to the server address in the handshake (like the "\0FML3\0" marker of Forge) instead of keeping it after the alias or target host
+    #[clap(long)]
+    strip_address_suffix: bool,
+
+    /// How to tell the backend the address of the player
+    #[clap(long, value_enum, default_value = "none")]
+    forwarding: Forwarding,
+
+    /// File with the secret for "--forwarding velocity" (forwarding-secret in the config of the backend)
+    #[clap(long = "forwarding-secret-file", value_parser = ForwardingSecret::read)]
+    forwarding_secret: Option<ForwardingSecret>,
+
+    /// Send a PROXY protocol header with the address of the player to the target (the target needs to expect it)
+    #[clap(long, value_enum)]
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
+
+    /// Expect a PROXY protocol header (v1 or v2) with the real address of the client from these IPs or CIDRs (e.g. a load balancer in front of the proxy)
+    #[clap(long, value_parser = Cidr::parse)]
+    accept_proxy_protocol_from: Vec<Cidr>,
+
+    /// Take the real address of the player and the hostname from handshakes forwarded by TCPShield (or other RealIP compatible proxies)
+    #[clap(long)]
+    tcpshield: bool,
+
+    /// Public key of TCPShield (PEM or base64) to verify the signed handshakes with. Handshakes without a valid and recent signature get rejected.
+    #[clap(long, value_parser = tcpshield::PublicKey::read, requires = "tcpshield")]
+    tcpshield_public_key: Option<tcpshield::PublicKey>,
+
     /// Output longer errors on connection fails (might also need to set env RUST_BACKTRACE=1)
     #[clap(short, long)]
     verbose: bool,
 
-    /// Specify polling rate (in ms), -1 removes polling
-    #[clap(short, long, default_value = "50")]
+    /// Max time (in ms) data may be held back to send it together, -1 sends immediately
+    #[clap(short, long, default_value = "50", allow_negative_numbers = true)]
     delay: i32,
 
+    /// Send held back data early once this many bytes are pending (0 to only use the delay)
+    #[clap(long, default_value = "16384")]
+    flush_bytes: usize,
+
+    /// Delay for data from client to target (instead of delay)
+    #[clap(long, allow_negative_numbers = true)]
+    upstream_delay: Option<i32>,
+
+    /// Delay for data from target to client (instead of delay)
+    #[clap(long, allow_negative_numbers = true)]
+    downstream_delay: Option<i32>,
+
+    /// Flush bytes for data from client to target (instead of flush_bytes)
+    #[clap(long)]
+    upstream_flush_bytes: Option<usize>,
+
+    /// Flush bytes for data from target to client (instead of flush_bytes)
+    #[clap(long)]
+    downstream_flush_bytes: Option<usize>,
+
+    /// Only hold data back while a direction receives more than this many bytes per second
+    #[clap(long)]
+    burst_bytes_per_sec: Option<u64>,
+
+    /// Only hold data back while a direction receives more than this many packets (reads) per second
+    #[clap(long)]
+    burst_reads_per_sec: Option<u64>,
+
+    /// Seconds the traffic needs to stay below the burst thresholds to forward immediately again
+    #[clap(long, default_value = "5")]
+    burst_cooldown: u64,
+
+    /// How to combine small packets into fewer TCP segments. Kernel-based strategies use the delay as uncork interval.
+    #[clap(long, value_enum, default_value = "userspace")]
+    coalescing: Coalescing,
+
+    /// Bytes of unsent data the kernel may hold per socket with "--coalescing notsent-lowat"
+    #[clap(long, default_value = "16384")]
+    notsent_lowat: u32,
+
+    /// Forward data with splice(2) without copying it, for connections that are not batched (Linux only)
+    #[clap(long)]
+    splice: bool,
+
     #[clap(short, long)]
     source_ip: Vec<String>,
+
+    /// Amount of threads which forward data of logged in connections
+    #[clap(long, default_value = "1")]
+    workers: usize,
+
+    /// Max bytes buffered per direction. Reading from the sending side pauses once reached.
+    #[clap(long, default_value = "4194304")]
+    max_buffered: usize,
+
+    /// Kick connections which stay at max_buffered for this many seconds without the receiving side accepting data
+    #[clap(long, default_value = "30")]
+    max_buffered_timeout: u64,
+
+    /// Seconds to keep forwarding the remaining direction after one side closed the connection
+    #[clap(long, default_value = "10")]
+    linger_timeout: u64,
+
+    /// Seconds a client may take for the handshake, status/ping or login start before getting disconnected
+    #[clap(long, default_value = "10")]
+    handshake_timeout: u64,
+
+    /// Seconds to wait for the target to accept a connection
+    #[clap(long, default_value = "5")]
+    connect_timeout: u64,
+
+    /// Which address family to try first when the target resolves to both (default: whatever the resolver returned first)
+    #[clap(long, value_enum)]
+    prefer_family: Option<AddressFamily>,
+
+    /// Close forwarded connections after this many seconds without data in either direction (0 to never close them)
+    #[clap(long, default_value = "300")]
+    idle_timeout: u64,
+
+    /// DNS server (IP or IP:Port) to query instead of the system resolver (SRV records are looked up via the first nameserver in /etc/resolv.conf by default)
+    #[clap(long, value_parser = dns::parse_server)]
+    dns_server: Option<SocketAddr>,
+
+    /// File in the format of /etc/hosts with fixed addresses for hosts, which are never looked up via DNS
+    #[clap(long)]
+    hosts_file: Option<String>,
+
+    /// Seconds to reuse resolved target addresses, which get refreshed in the background (0 to resolve for every connection)
+    #[clap(long, default_value = "60")]
+    dns_ttl: u64,
+
+    /// Serve Prometheus metrics via HTTP on this IP:Port combo (e.g. 127.0.0.1:9100)
+    #[clap(long)]
+    metrics_bind: Option<String>,
 }
 
-static SOURCES: LazyLock<Arc<Mutex<Vec<Arc<IpAddr>>>>> = LazyLock::new(|| Default::default());
+static SOURCES: LazyLock<Arc<Mutex<Vec<Arc<IpAddr>>>>> = LazyLock::new(Default::default);
 
 pub fn get_available_source_ip(v4: bool, v6: bool) -> Result<Option<Arc<IpAddr>>> {
     let sources = SOURCES.lock().expect("Lock SOURCES");
@@ -74,7 +252,7 @@ pub fn get_available_source_ip(v4: bool, v6: bool) -> Result<Option<Arc<IpAddr>>
 }
 
 fn main() -> Result<()> {
-    let opts = Opts::parse();
+    let opts = Arc::new(Opts::parse());
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "INFO");
     }
@@ -90,54 +268,221 @@ fn main() -> Result<()> {
             .push(Arc::new(source_ip.parse::<IpAddr>()?));
     }
 
+    if opts.forwarding == Forwarding::Velocity && opts.forwarding_secret.is_none() {
+        bail!("Velocity forwarding needs --forwarding-secret-file!");
+    }
+    opts.coalescing.ensure_supported()?;
+    if opts.splice && !cfg!(target_os = "linux") {
+        bail!("Splice is only supported on Linux!");
+    }
+    let reactor = Arc::new(Reactor::start(ReactorConfig {
+        workers: opts.workers,
+        verbose: opts.verbose,
+        max_buffered: opts.max_buffered.max(1),
+        max_buffered_timeout: Duration::from_secs(opts.max_buffered_timeout),
+        linger_timeout: Duration::from_secs(opts.linger_timeout),
+        idle_timeout: Some(Duration::from_secs(opts.idle_timeout)).filter(|t| !t.is_zero()),
+        coalescing: opts.coalescing,
+        notsent_lowat: opts.notsent_lowat,
+        splice: opts.splice,
+    })?);
+    let host_overrides = match &opts.hosts_file {
+        Some(path) => resolver::read_hosts_file(path)?,
+        None => Default::default(),
+    };
+    let resolver = Arc::new(Resolver::new(
+        Some(Duration::from_secs(opts.dns_ttl)).filter(|ttl| !ttl.is_zero()),
+        opts.dns_server,
+        host_overrides,
+    ));
+    resolver.start_refreshing()?;
+    let router = Arc::new(build_router(&opts));
+    for route in router.routes() {
+        route.backends.start_pinging({
+            let resolver = resolver.clone();
+            let opts = opts.clone();
+            let (alias_host, alias_port) = (route.alias_host.clone(), route.alias_port);
+            move |addr| {
+                let connect_timeout = Duration::from_secs(opts.connect_timeout);
+                let target = resolver.resolve(&addr.host, addr.port)?;
+                let target_addrs = connect::interleave(&target.addrs, opts.prefer_family);
+                let stream = connect::connect_any(&target_addrs, None, connect_timeout)?;
+                if let Some(version) = opts.send_proxy_protocol {
+                    (&stream).write_all(&proxy_protocol::header(version, None))?;
+                }
+                let (_, ping) = query_target_status_and_ping(
+                    &stream,
+                    alias_host.as_deref().unwrap_or(&addr.host),
+                    alias_port.unwrap_or(target.port),
+                    -1,
+                    Duration::from_secs(opts.handshake_timeout),
+                )?;
+                Ok(ping)
+            }
+        })?;
+    }
+    if let Some(metrics_bind) = &opts.metrics_bind {
+        metrics::serve(metrics_bind)?;
+    }
     let server = TcpListener::bind(&opts.bind).context("Bind own server")?;
     info!("Ready");
 
     loop {
         let (client, addr) = server.accept().context("Accept new client")?;
-        let target_host = opts.target_host.to_owned();
-        let target_port = opts.target_port.to_owned();
-        let alias_host = opts.alias_host.as_ref().cloned();
-        let alias_port = opts.alias_port.as_ref().cloned();
-        let verbose = opts.verbose;
-        let delay = opts.delay;
+        METRICS.accepted.fetch_add(1, Ordering::Relaxed);
+        METRICS.active.fetch_add(1, Ordering::Relaxed);
+        let opts = opts.clone();
+        let reactor = reactor.clone();
+        let resolver = resolver.clone();
+        let router = router.clone();
         std::thread::spawn(move || {
             let entered_span = span!(
                 Level::INFO,
                 "conn",
-                ip = addr.ip().to_string(),
+                ip = tracing::field::Empty,
+                real_ip = tracing::field::Empty,
                 user = tracing::field::Empty,
                 via_ip = tracing::field::Empty,
             )
             .entered();
-            info!("Connected to new client");
             let start = Instant::now();
-            match handle_client(
-                entered_span,
-                client,
-                target_host,
-                target_port,
-                alias_host,
-                alias_port,
-                delay,
-            ) {
-                Ok(_) => info!(
-                    "Connection finished after {}",
-                    format_duration(start.elapsed())
-                ),
-                Err(err) => {
-                    let duration_formatted = format_duration(start.elapsed());
-                    if verbose {
-                        error!("Finished with error after {duration_formatted}: {err:?}");
-                    } else {
-                        error!("Finished with error after {duration_formatted}: {err}");
+            // Everything the client sends before forwarding starts has to arrive in time
+            let handshake_deadline = start + Duration::from_secs(opts.handshake_timeout);
+            let result = client_addrs(&entered_span, &client, addr, handshake_deadline, &opts)
+                .and_then(|client_addrs| {
+                    handle_client(
+                        &entered_span,
+                        client,
+                        client_addrs,
+                        handshake_deadline,
+                        &opts,
+                        &resolver,
+                        &router,
+                    )
+                })
+                .map_err(timeouts::explain);
+            match result {
+                Ok(Some(connection)) => {
+                    // Hand over to the reactor, which logs once the connection is done
+                    let span = entered_span.exit();
+                    if let Err(err) = reactor.add(connection, span.clone(), start) {
+                        let _entered = span.entered();
+                        finish_connection(
+                            start,
+                            opts.verbose,
+                            Err(err).context("Start forwarding"),
+                            None,
+                        );
                     }
                 }
+                Ok(None) => finish_connection(start, opts.verbose, Ok(()), None),
+                Err(err) => finish_connection(start, opts.verbose, Err(err), None),
+            }
+        });
+    }
+}
+
+/// The routes from the options, with the target host (and further backends) as default route
+fn build_router(opts: &Opts) -> Router {
+    let routes = opts
+        .routes
+        .iter()
+        .map(|spec| {
+            let route = Route {
+                name: match spec.protocol {
+                    Some(protocol) => format!("{} (protocol {protocol})", spec.pattern),
+                    None => spec.pattern.to_string(),
+                },
+                protocol: spec.protocol,
+                backends: Arc::new(Backends::new(
+                    spec.backends.clone(),
+                    spec.balance.unwrap_or(opts.balance),
+                )),
+                alias_host: spec.alias_host.clone(),
+                alias_port: spec.alias_port,
+                motd_suffix: spec
+                    .motd_suffix
+                    .clone()
+                    .unwrap_or_else(|| opts.motd_suffix.clone()),
+                delay: spec.delay,
+            };
+            (spec.pattern.clone(), Arc::new(route))
+        })
+        .collect();
+    let default_backends = std::iter::once(BackendAddr {
+        host: opts.target_host.clone(),
+        port: opts.target_port,
+    })
+    .chain(opts.backends.iter().cloned())
+    .collect();
+    let default = Route {
+        name: "default".to_owned(),
+        protocol: None,
+        backends: Arc::new(Backends::new(default_backends, opts.balance)),
+        alias_host: opts.alias_host.clone(),
+        alias_port: opts.alias_port,
+        motd_suffix: opts.motd_suffix.clone(),
+        delay: None,
+    };
+    Router::new(routes, Arc::new(default))
+}
+
+/// A negative delay means to forward immediately
+fn flush_policy(opts: &Opts, delay: i32, flush_bytes: usize) -> FlushPolicy {
+    let burst =
+        (opts.burst_bytes_per_sec.is_some() || opts.burst_reads_per_sec.is_some()).then(|| {
+            BurstTrigger {
+                bytes_per_sec: opts.burst_bytes_per_sec,
+                reads_per_sec: opts.burst_reads_per_sec,
+                cooldown: Duration::from_secs(opts.burst_cooldown),
             }
         });
+    FlushPolicy {
+        deadline: u64::try_from(delay).ok().map(Duration::from_millis),
+        flush_bytes,
+        burst,
     }
 }
 
+/// Log the outcome of a connection. Must be called exactly once per accepted client.
+pub fn finish_connection(
+    start: Instant,
+    verbose: bool,
+    result: Result<()>,
+    stats: Option<ConnectionStats>,
+) {
+    METRICS.active.fetch_sub(1, Ordering::Relaxed);
+    if result.is_err() {
+        METRICS.failed.fetch_add(1, Ordering::Relaxed);
+    }
+    let duration_formatted = format_duration(start.elapsed());
+    let stats_formatted = stats.map(|stats| format!(" ({stats})")).unwrap_or_default();
+    match result {
+        Ok(_) => info!("Connection finished after {duration_formatted}{stats_formatted}"),
+        Err(err) => {
+            if verbose {
+                error!("Finished with error after {duration_formatted}{stats_formatted}: {err:?}");
+            } else {
+                error!("Finished with error after {duration_formatted}{stats_formatted}: {err}");
+            }
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
 fn format_duration(duration: Duration) -> String {
     let mut millis = duration.as_millis();
     let (mut hours, mut minutes, mut seconds) = (0, 0, 0);
@@ -181,18 +526,21 @@ fn format_duration(duration: Duration) -> String {
 }
 
 fn query_target_status_and_ping(
-    target_addr: SocketAddr,
-    target_host: &str,
-    target_port: u16,
-    alias_host: Option<&str>,
-    alias_port: Option<u16>,
+    target: &TcpStream,
+    server_address: &str,
+    server_port: u16,
     protocol_version: i32,
+    response_timeout: Duration,
 ) -> Result<(Value, u32)> {
-    let mut target = TcpStream::connect(target_addr)?;
+    let mut target = DeadlineStream::new(
+        target,
+        Instant::now() + response_timeout,
+        Timeout::TargetStatus(response_timeout),
+    );
     ClientHandshake {
         protocol_version: VarInt(protocol_version),
-        server_address: alias_host.unwrap_or(target_host).to_owned(),
-        server_port: alias_port.unwrap_or(target_port),
+        server_address: server_address.to_owned(),
+        server_port,
         next_state: VarInt(1), // = Status
     }
     .write_with_header_to(&mut target)?;
@@ -210,329 +558,543 @@ fn query_target_status_and_ping(
     Ok((serde_json::from_str(&status.json_response)?, ping))
 }
 
-fn handle_client(
-    entered_span: EnteredSpan,
-    mut client: TcpStream,
-    target_host: String,
-    target_port: u16,
-    alias_host: Option<String>,
-    alias_port: Option<u16>,
-    delay: i32,
-) -> Result<()> {
-    // Resolve host
-    // TODO: Improve on this ugliness!
-    let mut target_addr_v4 = None;
-    let mut target_addr_v6 = None;
+/// Address of the client and the one it connected to. Taken from the PROXY protocol header if the
+/// connection comes from a trusted proxy. Also records the client ip in the span.
+fn client_addrs(
+    entered_span: &EnteredSpan,
+    client: &TcpStream,
+    peer: SocketAddr,
+    handshake_deadline: Instant,
+    opts: &Opts,
+) -> Result<(SocketAddr, SocketAddr)> {
+    let local = client.local_addr()?;
+    let trusted = opts
+        .accept_proxy_protocol_from
+        .iter()
+        .any(|cidr| cidr.contains(peer.ip()));
+    if !trusted {
+        entered_span.record("ip", peer.ip().to_string());
+        info!("Connected to new client");
+        return Ok((peer, local));
+    }
 
-    for addr_info in
-        dns_lookup::getaddrinfo(Some(&target_host), None, None).map_err(|e| anyhow!("{:?}", e))?
-    {
-        let addr_info = addr_info?;
-        match addr_info.sockaddr.ip() {
-            IpAddr::V4(ip) => {
-                if target_addr_v4.is_none() {
-                    target_addr_v4 = Some(SocketAddrV4::new(ip, target_port));
+    let handshake_timeout = Duration::from_secs(opts.handshake_timeout);
+    let mut client_io = DeadlineStream::new(
+        client,
+        handshake_deadline,
+        Timeout::PreLogin(handshake_timeout),
+    );
+    match proxy_protocol::read_header(&mut client_io) {
+        Ok(Some((source, destination))) => {
+            entered_span.record("ip", source.ip().to_string());
+            info!("Connected to new client (via {})", peer.ip());
+            Ok((source, destination))
+        }
+        Ok(None) => {
+            entered_span.record("ip", peer.ip().to_string());
+            info!("Connected to new client (without address in PROXY protocol header)");
+            Ok((peer, local))
+        }
+        Err(err) => {
+            entered_span.record("ip", peer.ip().to_string());
+            Err(err).context("Read PROXY protocol header")
+        }
+    }
+}
+
+/// Status of the target (queried via the backends of the route), with the MOTD suffix of the route.
+/// `requested` is the server address and port the client asked for (if it told them).
+fn query_status(
+    route: &Route,
+    requested: Option<(&str, u16)>,
+    protocol_version: i32,
+    client_addrs: (SocketAddr, SocketAddr),
+    opts: &Opts,
+    resolver: &Resolver,
+) -> Result<Value> {
+    let handshake_timeout = Duration::from_secs(opts.handshake_timeout);
+    let connect_timeout = Duration::from_secs(opts.connect_timeout);
+    let requested_address = ServerAddress::parse(requested.map_or("", |(address, _)| address));
+    let (alias_host, alias_port) = (route.alias_host.as_deref(), route.alias_port);
+    let (backend, (mut status, ping)) = route.backends.try_each(|backend| {
+        let target = resolve_backend(resolver, backend)?;
+        let target_addrs = connect::interleave(&target.addrs, opts.prefer_family);
+        let stream = connect::connect_any(&target_addrs, None, connect_timeout)
+            .inspect_err(|_| METRICS.record_failure(FailureKind::Connect))
+            .context("Connect to target")?;
+        if let Some(version) = opts.send_proxy_protocol {
+            (&stream).write_all(&proxy_protocol::header(version, Some(client_addrs)))?;
+        }
+        query_target_status_and_ping(
+            &stream,
+            &requested_address.with_host(
+                alias_host.unwrap_or(&backend.addr.host),
+                opts.strip_address_suffix,
+            ),
+            alias_port.unwrap_or(target.port),
+            protocol_version,
+            handshake_timeout,
+        )
+    })?;
+    backend.record_ping(Some(ping));
+    let from = requested
+        .map(|(address, port)| format!(" from {address} (port {port})"))
+        .unwrap_or_default();
+    info!(
+        "Queried status{from} via {}, which reports version {}. Own ping was {ping} ms.",
+        backend.addr, status["version"]["name"]
+    );
+
+    // Add own suffix to status from target server
+    let suffix = route.motd_suffix.replace("{ping}", &ping.to_string());
+    if suffix.is_empty() {
+        // Nothing to add
+    } else if let Some(status) = status.as_object_mut() {
+        if let Some(description) = status.get_mut("description") {
+            match description {
+                Value::String(description_str) => description_str.push_str(&suffix),
+                Value::Object(description_obj) => {
+                    if let Some(Value::Array(extra)) = description_obj.get_mut("extra") {
+                        extra.push(Value::String(suffix));
+                    } else {
+                        bail!("\"description.extra\" in status was not an array!")
+                    }
                 }
-            }
-            IpAddr::V6(ip) => {
-                if target_addr_v6.is_none() {
-                    target_addr_v6 = Some(SocketAddrV6::new(ip, target_port, 0, 0));
+                Value::Array(description_arr) => description_arr.push(Value::String(suffix)),
+                _ => {
+                    bail!("\"description\" in status was neither a String, Object nor or an Array!")
                 }
             }
+        } else {
+            bail!("Status did not contain \"description\"!");
         }
+    } else {
+        bail!("Queries status was not a JSON-Object!");
     }
+    Ok(status)
+}
 
-    if target_addr_v4.is_none() && target_addr_v6.is_none() {
-        bail!("No address found for target host!");
+/// Replace the server address of a handshake forwarded by TCPShield with the hostname the player
+/// used and the client address with the one of the player
+fn unwrap_tcpshield(
+    entered_span: &EnteredSpan,
+    server_address: &mut String,
+    client_addrs: &mut (SocketAddr, SocketAddr),
+    opts: &Opts,
+) -> Result<()> {
+    let Some(tcpshield) =
+        TcpShieldAddress::parse(server_address).context("Invalid TCPShield server address")?
+    else {
+        if opts.tcpshield_public_key.is_some() {
+            bail!("Handshake did not come through TCPShield");
+        }
+        return Ok(());
+    };
+    if let Some(key) = &opts.tcpshield_public_key {
+        tcpshield
+            .verify(key)
+            .context("Verify TCPShield signature")?;
     }
-    let target_addr = if target_addr_v4.is_some() {
-        SocketAddr::V4(target_addr_v4.unwrap())
-    } else {
-        SocketAddr::V6(target_addr_v6.unwrap())
+    entered_span.record("real_ip", tcpshield.client_addr.ip().to_string());
+    client_addrs.0 = tcpshield.client_addr;
+    *server_address = tcpshield.server_address;
+    Ok(())
+}
+
+/// Status shown in the server list to clients with a protocol version outside of protocol_versions
+fn unsupported_version_status(opts: &Opts) -> Value {
+    serde_json::json!({
+        "version": { "name": "Unsupported version", "protocol": -1 },
+        "players": { "max": 0, "online": 0 },
+        "description": { "text": opts.unsupported_version_message },
+    })
+}
+
+/// Answer the server list ping of a client before 1.7 with the status of the target
+fn answer_legacy_ping(
+    entered_span: &EnteredSpan,
+    client_io: &mut DeadlineStream,
+    start: &[u8],
+    mut client_addrs: (SocketAddr, SocketAddr),
+    opts: &Opts,
+    resolver: &Resolver,
+    router: &Router,
+) -> Result<()> {
+    let mut ping = LegacyPing::read(client_io, start)
+        .inspect_err(|_| METRICS.record_failure(FailureKind::Handshake))
+        .context("Read legacy ping")?;
+    METRICS.status_requests.fetch_add(1, Ordering::Relaxed);
+    if opts.tcpshield {
+        // Only 1.6 sends an address, so earlier pings never come through TCPShield
+        let mut no_address = String::new();
+        let server_address = match &mut ping {
+            LegacyPing::V1_6 { host, .. } => host,
+            _ => &mut no_address,
+        };
+        unwrap_tcpshield(entered_span, server_address, &mut client_addrs, opts)
+            .inspect_err(|_| METRICS.record_failure(FailureKind::Handshake))?;
+    }
+    if let Some(supported) = opts
+        .protocol_versions
+        .filter(|supported| !supported.contains(LEGACY_PROTOCOL_VERSION))
+    {
+        info!("Legacy pings are not within {supported}, answering as unsupported.");
+        client_io.write_all(&ping.response(&unsupported_version_status(opts)))?;
+        return Ok(());
+    }
+
+    let (route, requested) = match &ping {
+        LegacyPing::V1_6 {
+            protocol_version,
+            host,
+            port,
+        } => {
+            info!("Client wants to query status of {host} (port {port}) with a legacy ping and uses legacy protocol version {protocol_version}");
+            (
+                router.route(host, LEGACY_PROTOCOL_VERSION),
+                Some((host.as_str(), *port)),
+            )
+        }
+        _ => {
+            info!("Client wants to query status with a legacy ping");
+            (router.route_without_host(), None)
+        }
     };
+    if !opts.routes.is_empty() {
+        info!("Using route {}", route.name);
+    }
+    let status = query_status(
+        route,
+        requested,
+        LEGACY_PROTOCOL_VERSION,
+        client_addrs,
+        opts,
+        resolver,
+    )?;
+    let handshake_timeout = Duration::from_secs(opts.handshake_timeout);
+    client_io.reset_deadline(Instant::now() + handshake_timeout);
+    client_io.write_all(&ping.response(&status))?;
+    info!("Done responding to client with legacy status.");
+    Ok(())
+}
+
+fn handle_client(
+    entered_span: &EnteredSpan,
+    client: TcpStream,
+    mut client_addrs: (SocketAddr, SocketAddr),
+    handshake_deadline: Instant,
+    opts: &Opts,
+    resolver: &Resolver,
+    router: &Router,
+) -> Result<Option<Connection>> {
+    let handshake_timeout = Duration::from_secs(opts.handshake_timeout);
+    // Shared with the PROXY protocol header, which doesn't get extra time
+    let mut client_io = DeadlineStream::new(
+        &client,
+        handshake_deadline,
+        Timeout::PreLogin(handshake_timeout),
+    );
+
+    // Clients before 1.7 send a legacy ping instead of a handshake
+    let mut start = [0u8; 3];
+    let peeked = client_io.peek(&mut start).context("Read handshake")?;
+    if LegacyPing::detect(&start[..peeked]) {
+        answer_legacy_ping(
+            entered_span,
+            &mut client_io,
+            &start[..peeked],
+            client_addrs,
+            opts,
+            resolver,
+            router,
+        )?;
+        return Ok(None);
+    }
 
     // Get first packet from client
-    let handshake =
-        ClientHandshake::read_with_header_from(&mut client).context("Read handshake")?;
+    let mut handshake = ClientHandshake::read_with_header_from(&mut client_io)
+        .inspect_err(|_| METRICS.record_failure(FailureKind::Handshake))
+        .context("Read handshake")?;
+    if opts.tcpshield {
+        unwrap_tcpshield(
+            entered_span,
+            &mut handshake.server_address,
+            &mut client_addrs,
+            opts,
+        )
+        .inspect_err(|_| METRICS.record_failure(FailureKind::Handshake))?;
+    }
+    let unsupported_version = opts
+        .protocol_versions
+        .filter(|supported| !supported.contains(*handshake.protocol_version));
+    let server_address = ServerAddress::parse(&handshake.server_address);
+    let route = router.route(&handshake.server_address, *handshake.protocol_version);
+    if !opts.routes.is_empty() && unsupported_version.is_none() {
+        info!("Using route {}", route.name);
+    }
+    let (backends, alias_host, alias_port) = (
+        &route.backends,
+        route.alias_host.as_deref(),
+        route.alias_port,
+    );
     if handshake.next_state == VarInt(1 /*Status*/) {
+        METRICS.status_requests.fetch_add(1, Ordering::Relaxed);
         info!(
             "Client wants to query status of {} (port {}) and uses protocol version {}",
             handshake.server_address, handshake.server_port, handshake.protocol_version
         );
 
-        ClientStatusRequest::read_with_header_from(&mut client)?;
+        ClientStatusRequest::read_with_header_from(&mut client_io)?;
 
-        // Client wants status, forward and modify from target
-        let (mut status, ping) = query_target_status_and_ping(
-            target_addr,
-            &target_host,
-            target_port,
-            alias_host.as_ref().map(|s| s.as_str()),
-            alias_port,
-            *handshake.protocol_version,
-        )?;
-        info!(
-            "Queried status from {} (port {}). Own ping was {ping} ms.",
-            handshake.server_address, handshake.server_port
-        );
-
-        // Add own suffix to status from target server
-        let suffix = format!("§8[§9Stupid MC Proxy: §3{ping}ms§8]");
-        if let Some(status) = status.as_object_mut() {
-            if let Some(description) = status.get_mut("description") {
-                match description {
-                    Value::String(description_str) => description_str.push_str(&suffix),
-                    Value::Object(description_obj) => {
-                        if let Some(Value::Array(extra)) = description_obj.get_mut("extra") {
-                            extra.push(Value::String(suffix));
-                        } else {
-                            bail!("\"description.extra\" in status was not an array!")
-                        }
-                    }
-                    Value::Array(description_arr) => description_arr.push(Value::String(suffix)),
-                    _ => bail!(
-                        "\"description\" in status was neither a String, Object nor or an Array!"
-                    ),
-                }
-            } else {
-                bail!("Status did not contain \"description\"!");
-            }
+        let status = if let Some(supported) = unsupported_version {
+            info!("Protocol version is not within {supported}, answering as unsupported.");
+            unsupported_version_status(opts)
         } else {
-            bail!("Queries status was not a JSON-Object!");
-        }
+            // Client wants status, forward and modify from target
+            query_status(
+                route,
+                Some((&handshake.server_address, handshake.server_port)),
+                *handshake.protocol_version,
+                client_addrs,
+                opts,
+                resolver,
+            )?
+        };
+        // Querying the backends doesn't count towards the time the client has
+        client_io.reset_deadline(Instant::now() + handshake_timeout);
         ServerStatusResponsePacket {
             json_response: serde_json::to_string(&status)?,
         }
-        .write_with_header_to(&mut client)?;
+        .write_with_header_to(&mut client_io)?;
 
-        let ping_request = ClientStatusPing::read_with_header_from(&mut client)?;
+        let ping_request = ClientStatusPing::read_with_header_from(&mut client_io)?;
         ServerStatusPongPacket {
             payload: ping_request.payload,
         }
-        .write_with_header_to(&mut client)?;
+        .write_with_header_to(&mut client_io)?;
         info!("Done responding to client with status.");
-        return Ok(());
+        return Ok(None);
         // SEND TO CLIENT
     } else if handshake.next_state != VarInt(2 /*Login*/) {
+        METRICS.record_failure(FailureKind::Handshake);
         bail!(
             "Client requested next state {}, which is not supported!",
             handshake.next_state
         );
     }
 
+    METRICS.logins.fetch_add(1, Ordering::Relaxed);
     info!(
         "Client wants to login to {} (port {}) and uses protocol version {}",
         handshake.server_address, handshake.server_port, handshake.protocol_version
     );
+    if let Some(supported) = unsupported_version {
+        METRICS.record_failure(FailureKind::UnsupportedVersion);
+        ServerLoginDisconnect {
+            reason: serde_json::json!({ "text": opts.unsupported_version_message }),
+        }
+        .write_with_header_to(&mut client_io)
+        .context("Kick client with unsupported protocol version")?;
+        bail!(
+            "Kicked, because protocol version {} is not within {supported}",
+            *handshake.protocol_version
+        );
+    }
 
-    let source_ip =
-        match get_available_source_ip(target_addr_v4.is_some(), target_addr_v6.is_some()) {
-            Ok(ip) => {
-                if let Some(ref ip) = ip {
-                    entered_span.record("via_ip", ip.to_string());
-                }
-                ip
-            }
-            Err(err) => {
-                ServerLoginDisconnect {
-                    reason: serde_json::json!({ "text": format!("StupidMCProxy Error: {err}") }),
-                }
-                .write_with_header_to(&mut client)
-                .context("Kick client due to error obtaining new source ip")?;
-                return Err(err);
-            }
-        };
+    // Read before connecting, so trying the backends doesn't count towards the time the client
+    // has to send it (the target sees nothing until the initial packets get written anyway)
+    let (login_first_packet_id, login_first_packet_data) =
+        protocol::read_raw_packet_id_and_data(&mut client_io)?;
+    if login_first_packet_id != ClientLoginStart::packet_id() {
+        bail!(
+            "Expect to receive Packet LoginStart (id {}, but got {} instead)!",
+            ClientLoginStart::packet_id(),
+            login_first_packet_id
+        );
+    }
 
-    //let mut target = TcpStream::connect(target_addr).context("Connect to target")?;
-    let mut target = match source_ip.as_ref().map(|ip| ip.as_ref()) {
-        Some(IpAddr::V4(addr)) => {
-            let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-            socket.set_reuse_address(true)?;
-            socket.set_reuse_port(true)?;
-            //socket.set_tcp_cork(true)?;
-            socket.bind(&SocketAddr::V4(SocketAddrV4::new(*addr, 0)).into())?;
-            socket
-                .connect(
-                    &target_addr_v4
-                        .ok_or(anyhow!("Expected resolved target IPv4"))?
-                        .into(),
-                )
-                .context("Connect to target (IPv4)")?;
-            socket.into()
-        }
-        Some(IpAddr::V6(addr)) => {
-            let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
-            socket.set_reuse_address(true)?;
-            socket.set_reuse_port(true)?;
-            //socket.set_tcp_cork(true)?;
-            socket.bind(&SocketAddr::V6(SocketAddrV6::new(*addr, 0, 0, 0)).into())?;
-            socket
-                .connect(
-                    &target_addr_v6
-                        .ok_or(anyhow!("Expected resolved target IPv6"))?
-                        .into(),
-                )
-                .context("Connect to target (IPv6)")?;
-            socket.into()
+    let (username, uuid) = if let Ok(login_start) =
+        ClientLoginStart::from_cursor(&mut Cursor::new(login_first_packet_data.as_slice()))
+    {
+        info!(
+            "Client claims to be {} ({})",
+            login_start.username, login_start.uuid
+        );
+        entered_span.record("user", &login_start.username);
+        (login_start.username, Some(login_start.uuid))
+    } else {
+        let login_start = ClientLoginStartOnlyName::from_cursor(&mut Cursor::new(
+            login_first_packet_data.as_slice(),
+        ))?;
+        info!(
+            "Client claims to be {} (old format, so likely no uuid sent)",
+            login_start.username
+        );
+        entered_span.record("user", &login_start.username);
+        (login_start.username, None)
+    };
+
+    let connected =
+        backends.try_each(|backend| connect_backend(entered_span, opts, resolver, backend));
+    // Trying the backends doesn't count towards the time the client has
+    client_io.reset_deadline(Instant::now() + handshake_timeout);
+    let (backend, (mut target, source_ip, target_port)) = match connected {
+        Ok(connected) => connected,
+        Err(err) => {
+            ServerLoginDisconnect {
+                reason: serde_json::json!({ "text": format!("StupidMCProxy Error: {err}") }),
+            }
+            .write_with_header_to(&mut client_io)
+            .context("Kick client after no backend was reachable")?;
+            return Err(err);
         }
-        _ => TcpStream::connect(target_addr).context("Connect to target")?,
     };
 
-    info!("Connected to target.");
+    info!("Connected to target {}.", backend.addr);
 
     // Next state: Login (2)
     // Forward handshake with modified (server/host) to target
+    let host = alias_host.unwrap_or(&backend.addr.host);
+    let uuid = uuid.unwrap_or_else(|| forwarding::offline_uuid(&username));
     let mut initial_packets_buffer = Cursor::new(Vec::<u8>::new());
+    if let Some(version) = opts.send_proxy_protocol {
+        initial_packets_buffer.write_all(&proxy_protocol::header(version, Some(client_addrs)))?;
+    }
     ClientHandshake {
         protocol_version: handshake.protocol_version,
         next_state: VarInt(2),
-        server_port: alias_port.unwrap_or(target_port).to_owned(),
-        server_address: alias_host.unwrap_or(target_host).to_owned(),
+        server_port: alias_port.unwrap_or(target_port),
+        server_address: opts.forwarding.handshake_address(
+            &server_address,
+            host,
+            opts.strip_address_suffix,
+            client_addrs.0.ip(),
+            uuid,
+        ),
     }
     .write_with_header_to(&mut initial_packets_buffer)
     .context("Create handshake packet")?;
 
-    {
-        let (login_first_packet_id, login_first_packet_data) =
-            protocol::read_raw_packet_id_and_data(&mut client)?;
-        if login_first_packet_id != ClientLoginStart::packet_id() {
-            bail!(
-                "Expect to receive Packet LoginStart (id {}, but got {} instead)!",
-                ClientLoginStart::packet_id(),
-                login_first_packet_id
-            );
-        }
+    // Forward exact received packet data to target (can vary between version)
+    let mut cursor = Cursor::new(Vec::with_capacity(4 + login_first_packet_data.len()));
+    login_first_packet_id.write_as_mc_type(&mut cursor)?;
+    cursor.write_all(&login_first_packet_data)?;
+    VarInt(cursor.position() as i32).write_as_mc_type(&mut initial_packets_buffer)?;
+    initial_packets_buffer.write_all(&cursor.into_inner())?;
 
-        if let Ok(login_start) =
-            ClientLoginStart::from_cursor(&mut Cursor::new(login_first_packet_data.as_slice()))
-        {
-            info!(
-                "Client claims to be {} ({})",
-                login_start.username, login_start.uuid
-            );
-            entered_span.record("user", login_start.username);
-        } else {
-            let login_start = ClientLoginStartOnlyName::from_cursor(&mut Cursor::new(
-                login_first_packet_data.as_slice(),
-            ))?;
-            info!(
-                "Client claims to be {} (old format, so likely no uuid sent)",
-                login_start.username
-            );
-            entered_span.record("user", login_start.username);
-        }
-
-        // Forward exact received packet data to target (can vary between version)
-        let mut cursor = Cursor::new(Vec::with_capacity(4 + login_first_packet_data.len()));
-        login_first_packet_id.write_as_mc_type(&mut cursor)?;
-        cursor.write_all(&login_first_packet_data)?;
-        VarInt(cursor.position() as i32).write_as_mc_type(&mut initial_packets_buffer)?;
-        initial_packets_buffer.write_all(&cursor.into_inner())?;
+    // Combining the first 2 packets is needed to bypass some weird TCPShield bot detection stuff
+    initial_packets_buffer.seek(SeekFrom::Start(0))?;
+    target.write_all(&initial_packets_buffer.into_inner())?;
 
-        // Combining the first 2 packets is needed to bypass some weird TCPShield bot detection stuff
-        initial_packets_buffer.seek(SeekFrom::Start(0))?;
-        target.write_all(&initial_packets_buffer.into_inner())?;
+    if let (Forwarding::Velocity, Some(secret)) = (opts.forwarding, &opts.forwarding_secret) {
+        let player_info =
+            forwarding::velocity_player_info(&secret.0, client_addrs.0.ip(), uuid, &username)?;
+        answer_velocity_request(&target, &mut client_io, player_info, handshake_timeout)?;
     }
 
-    // Uncork
-    /*{
-        let socket = unsafe { Socket::from_raw_fd(target.as_raw_fd()) };
-        socket.set_tcp_cork(false)?;
-        let _ = socket.into_raw_fd(); // Don't close
-    }*/
     info!("Proxying raw data to each other...");
 
     client.set_nodelay(true)?;
     target.set_nodelay(true)?;
-    client.set_nonblocking(true)?;
-    target.set_nonblocking(true)?;
 
-    let mut buf = vec![0u8; 4096 * 16];
-    let mut buf_2 = Vec::with_capacity(4096 * 32);
-    loop {
-        if delay < 0 {
-            let poller = Poller::new()?;
-            let mut events = Events::new();
-            unsafe { poller.add(&client, Event::readable(0))? };
-            unsafe { poller.add(&target, Event::readable(0))? };
-            //events.clear();
-            poller.wait(&mut events, None)?;
-        } else {
-            std::thread::sleep(Duration::from_millis(delay as u64));
-        }
+    Ok(Some(Connection {
+        client,
+        target,
+        source_ip,
+        backend: BackendLease::new(backend),
+        upstream_flush: flush_policy(
+            opts,
+            route.delay.or(opts.upstream_delay).unwrap_or(opts.delay),
+            opts.upstream_flush_bytes.unwrap_or(opts.flush_bytes),
+        ),
+        downstream_flush: flush_policy(
+            opts,
+            route.delay.or(opts.downstream_delay).unwrap_or(opts.delay),
+            opts.downstream_flush_bytes.unwrap_or(opts.flush_bytes),
+        ),
+    }))
+}
 
-        // Client -> Target
-        buf_2.clear();
-        loop {
-            match client.read(&mut buf) {
-                Ok(read) => {
-                    if read == 0 {
-                        info!("Connection terminated by client!");
-                        return Ok(());
-                    }
-                    buf[..read].iter().for_each(|b| buf_2.push(*b));
-                }
-                Err(err) => {
-                    match err.kind() {
-                        std::io::ErrorKind::WouldBlock => {
-                            break; // Done reading
-                        }
-                        _ => return Err(err).context("Read client"),
-                    }
-                }
-            }
-        }
-        let mut pos = 0;
-        while pos < buf_2.len() {
-            match target.write(&buf_2[pos..]) {
-                Ok(written) => {
-                    pos += written;
-                }
-                Err(err) => match err.kind() {
-                    std::io::ErrorKind::WouldBlock => {
-                        std::thread::sleep(Duration::from_millis(25));
-                        continue;
-                    }
-                    _ => return Err(err).context("Write to target"),
-                },
-            }
-        }
+fn resolve_backend(resolver: &Resolver, backend: &Backend) -> Result<resolver::ResolvedTarget> {
+    resolver
+        .resolve(&backend.addr.host, backend.addr.port)
+        .inspect_err(|_| METRICS.record_failure(FailureKind::Dns))
+}
 
-        // Target -> Client
-        buf_2.clear();
-        loop {
-            match target.read(&mut buf) {
-                Ok(read) => {
-                    if read == 0 {
-                        info!("Connection terminated by target!");
-                        return Ok(());
-                    }
-                    buf[..read].iter().for_each(|b| buf_2.push(*b));
-                }
-                Err(err) => {
-                    match err.kind() {
-                        std::io::ErrorKind::WouldBlock => {
-                            break; // Done reading
-                        }
-                        _ => return Err(err).context("Read target"),
-                    }
-                }
-            }
-        }
-        let mut pos = 0;
-        while pos < buf_2.len() {
-            match client.write(&buf_2[pos..]) {
-                Ok(written) => {
-                    pos += written;
-                }
-                Err(err) => match err.kind() {
-                    std::io::ErrorKind::WouldBlock => {
-                        std::thread::sleep(Duration::from_millis(25));
-                        continue;
-                    }
-                    _ => return Err(err).context("Write to client"),
-                },
+/// Resolve the backend and connect to it via a free source ip (if any are configured).
+/// Returns the connection, its source ip and the port that got used.
+fn connect_backend(
+    entered_span: &EnteredSpan,
+    opts: &Opts,
+    resolver: &Resolver,
+    backend: &Backend,
+) -> Result<(TcpStream, Option<Arc<IpAddr>>, u16)> {
+    let target = resolve_backend(resolver, backend)?;
+    let target_addrs = connect::interleave(&target.addrs, opts.prefer_family);
+
+    let source_ip = get_available_source_ip(
+        target_addrs.iter().any(SocketAddr::is_ipv4),
+        target_addrs.iter().any(SocketAddr::is_ipv6),
+    )
+    .inspect_err(|_| METRICS.record_failure(FailureKind::OutOfSourceIps))?;
+    if let Some(ref ip) = source_ip {
+        entered_span.record("via_ip", ip.to_string());
+    }
+
+    // The source ip decides which family can be used
+    let target_addrs: Vec<SocketAddr> = match source_ip.as_deref() {
+        Some(source_ip) => target_addrs
+            .into_iter()
+            .filter(|addr| addr.is_ipv4() == source_ip.is_ipv4())
+            .collect(),
+        None => target_addrs,
+    };
+    let stream = connect::connect_any(
+        &target_addrs,
+        source_ip.as_deref().copied(),
+        Duration::from_secs(opts.connect_timeout),
+    )
+    .inspect_err(|_| METRICS.record_failure(FailureKind::Connect))
+    .context("Connect to target")?;
+    Ok((stream, source_ip, target.port))
+}
+
+/// Answer the player info request of a backend with Velocity modern forwarding. Anything else the
+/// backend sends first (like a kick, because forwarding is not enabled) gets passed on to the
+/// client as is.
+fn answer_velocity_request(
+    target: &TcpStream,
+    client: &mut impl Write,
+    player_info: Vec<u8>,
+    timeout: Duration,
+) -> Result<()> {
+    let mut target_io = DeadlineStream::new(
+        target,
+        Instant::now() + timeout,
+        Timeout::TargetLogin(timeout),
+    );
+    let (packet_id, packet_data) = protocol::read_raw_packet_id_and_data(&mut target_io)
+        .context("Read first login packet from target")?;
+    if packet_id == ServerLoginPluginRequest::packet_id() {
+        let request =
+            ServerLoginPluginRequest::from_cursor(&mut Cursor::new(packet_data.as_slice()))?;
+        if request.channel == forwarding::VELOCITY_CHANNEL {
+            ClientLoginPluginResponse {
+                message_id: request.message_id,
+                successful: true,
+                data: Some(player_info),
             }
+            .write_with_header_to(&mut target_io)?;
+            info!("Forwarded player info to target.");
+            return Ok(());
         }
     }
+
+    info!("Target did not request the player info. Is Velocity forwarding enabled on it?");
+    let mut packet = Vec::with_capacity(4 + packet_data.len());
+    packet_id.write_as_mc_type(&mut packet)?;
+    packet.extend_from_slice(&packet_data);
+    VarInt(packet.len() as i32).write_as_mc_type(client)?;
+    client.write_all(&packet)?;
+    Ok(())
 }
@@ -0,0 +1,279 @@
+use crate::stats::{self, DirectionStats};
+use crate::SOURCES;
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Kinds of errors which are counted separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Dns,
+    OutOfSourceIps,
+    Connect,
+    Handshake,
+    UnsupportedVersion,
+}
+
+impl FailureKind {
+    const ALL: [FailureKind; 5] = [
+        FailureKind::Dns,
+        FailureKind::OutOfSourceIps,
+        FailureKind::Connect,
+        FailureKind::Handshake,
+        FailureKind::UnsupportedVersion,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            FailureKind::Dns => "dns",
+            FailureKind::OutOfSourceIps => "out_of_source_ips",
+            FailureKind::Connect => "connect",
+            FailureKind::Handshake => "handshake",
+            FailureKind::UnsupportedVersion => "unsupported_version",
+        }
+    }
+}
+
+pub struct Metrics {
+    pub accepted: AtomicU64,
+    pub active: AtomicI64,
+    pub status_requests: AtomicU64,
+    pub logins: AtomicU64,
+    /// Connections which finished with any error
+    pub failed: AtomicU64,
+    failures: [AtomicU64; FailureKind::ALL.len()],
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            accepted: AtomicU64::new(0),
+            active: AtomicI64::new(0),
+            status_requests: AtomicU64::new(0),
+            logins: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            failures: [const { AtomicU64::new(0) }; FailureKind::ALL.len()],
+        }
+    }
+
+    pub fn record_failure(&self, kind: FailureKind) {
+        self.failures[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Everything in the Prometheus text format
+    fn render(&self) -> String {
+        let mut out = String::with_capacity(4096);
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP stupid_mc_proxy_{name} {help}");
+            let _ = writeln!(out, "# TYPE stupid_mc_proxy_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "stupid_mc_proxy_{name}{labels} {value}");
+            }
+        };
+
+        metric(
+            "connections_active",
+            "gauge",
+            "Currently open client connections",
+            &[("", self.active.load(Ordering::Relaxed).max(0) as u64)],
+        );
+        metric(
+            "connections_accepted_total",
+            "counter",
+            "Accepted client connections",
+            &[("", self.accepted.load(Ordering::Relaxed))],
+        );
+        metric(
+            "connections_failed_total",
+            "counter",
+            "Connections which finished with an error",
+            &[("", self.failed.load(Ordering::Relaxed))],
+        );
+        metric(
+            "requests_total",
+            "counter",
+            "Handshakes by requested next state",
+            &[
+                (
+                    "{state=\"status\"}",
+                    self.status_requests.load(Ordering::Relaxed),
+                ),
+                ("{state=\"login\"}", self.logins.load(Ordering::Relaxed)),
+            ],
+        );
+
+        let failure_labels: Vec<String> = FailureKind::ALL
+            .iter()
+            .map(|kind| format!("{{kind=\"{}\"}}", kind.label()))
+            .collect();
+        let failures: Vec<(&str, u64)> = FailureKind::ALL
+            .iter()
+            .zip(&failure_labels)
+            .map(|(kind, label)| {
+                (
+                    label.as_str(),
+                    self.failures[*kind as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect();
+        metric("errors_total", "counter", "Errors by kind", &failures);
+
+        let upstream = stats::UPSTREAM_TOTALS.snapshot();
+        let downstream = stats::DOWNSTREAM_TOTALS.snapshot();
+        let per_direction = |value: fn(&DirectionStats) -> u64| {
+            [
+                ("{direction=\"upstream\"}", value(&upstream)),
+                ("{direction=\"downstream\"}", value(&downstream)),
+            ]
+        };
+        metric(
+            "forwarded_bytes_total",
+            "counter",
+            "Bytes written to the other side (upstream is client to target)",
+            &per_direction(|stats| stats.bytes_written),
+        );
+        metric(
+            "read_syscalls_total",
+            "counter",
+            "Successful reads while forwarding",
+            &per_direction(|stats| stats.reads),
+        );
+        metric(
+            "write_syscalls_total",
+            "counter",
+            "Successful writes while forwarding",
+            &per_direction(|stats| stats.writes),
+        );
+        metric(
+            "flushes_total",
+            "counter",
+            "Batches of collected data that were sent",
+            &per_direction(|stats| stats.flushes),
+        );
+
+        let (sources_total, sources_in_use) = {
+            let sources = SOURCES.lock().expect("Lock SOURCES");
+            let in_use = sources.iter().filter(|ip| Arc::strong_count(ip) > 1);
+            (sources.len() as u64, in_use.count() as u64)
+        };
+        metric(
+            "source_ips",
+            "gauge",
+            "Configured source IPs",
+            &[("", sources_total)],
+        );
+        metric(
+            "source_ips_in_use",
+            "gauge",
+            "Source IPs currently used by a connection",
+            &[("", sources_in_use)],
+        );
+        out
+    }
+}
+
+/// Serve the metrics over HTTP on a separate thread
+pub fn serve(bind: &str) -> Result<()> {
+    let listener = TcpListener::bind(bind).context("Bind metrics server")?;
+    info!("Serving metrics on http://{bind}/metrics");
+    std::thread::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .context("Accept metrics client")
+                    .and_then(handle_request);
+                if let Err(err) = result {
+                    error!("Failed to serve metrics: {err}");
+                }
+            }
+        })
+        .context("Spawn metrics server")?;
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, body) = if path == "/metrics" || path == "/" {
+        ("200 OK", METRICS.render())
+    } else {
+        ("404 Not Found", "Not found. Try /metrics\n".to_owned())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn render_exposition_format() {
+        let metrics = Metrics::new();
+        metrics.accepted.fetch_add(3, Ordering::Relaxed);
+        metrics.active.fetch_add(1, Ordering::Relaxed);
+        metrics.logins.fetch_add(2, Ordering::Relaxed);
+        metrics.record_failure(FailureKind::Handshake);
+        let out = metrics.render();
+        let lines: Vec<&str> = out.lines().collect();
+
+        for expected in [
+            "# HELP stupid_mc_proxy_connections_active Currently open client connections",
+            "# TYPE stupid_mc_proxy_connections_active gauge",
+            "stupid_mc_proxy_connections_active 1",
+            "# TYPE stupid_mc_proxy_connections_accepted_total counter",
+            "stupid_mc_proxy_connections_accepted_total 3",
+            "stupid_mc_proxy_connections_failed_total 0",
+            "stupid_mc_proxy_requests_total{state=\"status\"} 0",
+            "stupid_mc_proxy_requests_total{state=\"login\"} 2",
+            "stupid_mc_proxy_errors_total{kind=\"handshake\"} 1",
+            "stupid_mc_proxy_errors_total{kind=\"out_of_source_ips\"} 0",
+            "# TYPE stupid_mc_proxy_forwarded_bytes_total counter",
+            "# TYPE stupid_mc_proxy_source_ips_in_use gauge",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "Missing \"{expected}\" in:\n{out}"
+            );
+        }
+        // Traffic totals are shared with other tests, so only their labels are checked
+        for direction in ["upstream", "downstream"] {
+            let prefix = format!("stupid_mc_proxy_flushes_total{{direction=\"{direction}\"}} ");
+            assert!(lines.iter().any(|line| line.starts_with(&prefix)));
+        }
+
+        // Every sample belongs to the metric announced right before it
+        let mut current = "";
+        for line in lines {
+            if let Some(name) = line.strip_prefix("# TYPE ") {
+                current = name.split(' ').next().unwrap();
+            } else if !line.starts_with('#') {
+                let name = line.split(['{', ' ']).next().unwrap();
+                assert_eq!(name, current);
+            }
+        }
+    }
+}
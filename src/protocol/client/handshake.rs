@@ -44,8 +44,8 @@ pub mod test {
         };
         let mut data_cur = Cursor::new(Vec::<u8>::new());
         test_handshake.write_to(&mut data_cur).unwrap();
-        data_cur.set_position(0);
-        let read_back = ClientHandshake::from_cursor(&mut data_cur.into()).unwrap();
+        let read_back =
+            ClientHandshake::from_cursor(&mut Cursor::new(data_cur.get_ref().as_slice())).unwrap();
         assert_eq!(read_back, test_handshake);
     }
 }
@@ -3,7 +3,7 @@ use crate::protocol::types::MinecraftDataType;
 use self::types::VarInt;
 use anyhow::{bail, Context};
 use std::convert::TryFrom;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Read};
 
 pub mod client;
 pub mod server;
@@ -52,7 +52,7 @@ pub trait Packet<T> {
         Self::packet_id().write_as_mc_type(&mut content)?;
         self.write_to(&mut content)?;
         VarInt(i32::try_from(content.position())?).write_as_mc_type(writer)?;
-        writer.write_all(&mut content.into_inner())?;
+        writer.write_all(&content.into_inner())?;
         Ok(())
     }
 }
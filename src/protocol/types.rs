@@ -32,6 +32,7 @@ pub struct VarLong(pub i64);
 
 // Position
 // Angle
+#[allow(clippy::upper_case_acronyms)]
 pub type UUID = uuid::Uuid;
 // Array of X => Vec<MinecraftDataType>
 // X Enum
@@ -86,7 +87,7 @@ impl MinecraftDataType for VarInt {
         loop {
             if (value & 0xFFFFFF80) == 0 {
                 bytes.push((value & 0xFF) as u8);
-                return Ok(writer.write_all(&mut bytes)?);
+                return Ok(writer.write_all(&bytes)?);
             }
 
             bytes.push(((value & 0x7F | 0x80) & 0xFF) as u8);
@@ -169,7 +170,7 @@ impl MinecraftDataType for VarLong {
         loop {
             if (value & 0xFFFFFFFFFFFFFF80) == 0 {
                 bytes.push((value & 0xFF) as u8);
-                return Ok(writer.write_all(&mut bytes)?);
+                return Ok(writer.write_all(&bytes)?);
             }
 
             bytes.push(((value & 0x7F | 0x80) & 0xFF) as u8);
@@ -336,11 +337,11 @@ impl<T: MinecraftDataType> MinecraftDataType for Vec<T> {
 impl<T: MinecraftDataType> MinecraftDataType for Option<T> {
     fn read_as_mc_type<R: Read>(reader: &mut R) -> Result<Self> {
         let is_present = Boolean::read_as_mc_type(reader)?;
-        return Ok(if is_present {
+        Ok(if is_present {
             Some(T::read_as_mc_type(reader)?)
         } else {
             None
-        });
+        })
     }
 
     fn write_as_mc_type<W: Write>(&self, writer: &mut W) -> Result<()> {
@@ -362,7 +363,7 @@ impl MinecraftDataType for String {
     }
 
     fn write_as_mc_type<W: Write>(&self, writer: &mut W) -> Result<()> {
-        VarInt(i32::try_from(self.as_bytes().len())?).write_as_mc_type(writer)?;
+        VarInt(i32::try_from(self.len())?).write_as_mc_type(writer)?;
         writer.write_all(self.as_bytes())?;
         Ok(())
     }
@@ -552,7 +553,7 @@ impl Display for Identifier {
 
 impl MinecraftDataType for Identifier {
     fn read_as_mc_type<R: Read>(reader: &mut R) -> Result<Self> {
-        Ok(String::read_as_mc_type(reader)?.parse()?)
+        String::read_as_mc_type(reader)?.parse()
     }
     fn write_as_mc_type<W: Write>(&self, writer: &mut W) -> Result<()> {
         self.to_string().write_as_mc_type(writer)
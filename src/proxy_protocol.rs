@@ -0,0 +1,290 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Start of every PROXY protocol v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProxyProtocolVersion {
+    /// Human readable
+    V1,
+    /// Binary
+    V2,
+}
+
+/// PROXY protocol header with the address of the client and the address it connected to. Without
+/// addresses, the header marks the connection as made by the proxy itself (e.g. for pings).
+pub fn header(version: ProxyProtocolVersion, addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let addrs = addrs.map(|(source, destination)| same_family(source, destination));
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let Some((source, destination)) = addrs else {
+                header.extend_from_slice(&[0x20, 0x00, 0, 0]); // Local, unspecified family
+                return header;
+            };
+            header.push(0x21); // Version 2, proxied
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    header.push(0x11); // TCP over IPv4
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&source_ip.octets());
+                    header.extend_from_slice(&destination_ip.octets());
+                }
+                (source_ip, destination_ip) => {
+                    header.push(0x21); // TCP over IPv6
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&to_ipv6(source_ip).octets());
+                    header.extend_from_slice(&to_ipv6(destination_ip).octets());
+                }
+            }
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+/// IP network like 10.0.0.0/8 (a single IP without prefix length)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Self, String> {
+        let invalid = || format!("Expected IP or CIDR like 10.0.0.0/8, got \"{cidr}\"");
+        let (ip, prefix_len) = cidr.split_once('/').unwrap_or((cidr, ""));
+        let network = ip.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            "" => max_len,
+            len => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(invalid)?,
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let prefix_matches = |network: u128, ip: u128, bits: u8| {
+            let shift = bits - self.prefix_len;
+            shift == bits || network >> shift == ip >> shift
+        };
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(network.to_bits().into(), ip.to_bits().into(), 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.to_bits(), ip.to_bits(), 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Read the PROXY protocol header (v1 or v2), which a trusted proxy sends before anything else.
+/// Returns the address of the client and the one it connected to, unless the proxy did not
+/// forward a TCP connection (e.g. for its own health checks).
+pub fn read_header(reader: &mut impl Read) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    // Shortest possible v1 header is "PROXY UNKNOWN\r\n", so this never reads too much
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start)?;
+    if start == V2_SIGNATURE {
+        read_v2(reader)
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(reader, &start)
+    } else {
+        bail!("Expected a PROXY protocol header")
+    }
+}
+
+fn read_v1(reader: &mut impl Read, start: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    // Max length of a v1 header is 107 bytes
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= 107 {
+            bail!("PROXY protocol v1 header too long");
+        }
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).context("Invalid PROXY header")?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts[..] {
+        ["PROXY", "TCP4" | "TCP6", source_ip, destination_ip, source_port, destination_port] => {
+            let addr = |ip: &str, port: &str| -> Result<SocketAddr> {
+                Ok(SocketAddr::new(ip.parse()?, port.parse()?))
+            };
+            Ok(Some((
+                addr(source_ip, source_port).context("Invalid PROXY header")?,
+                addr(destination_ip, destination_port).context("Invalid PROXY header")?,
+            )))
+        }
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => bail!("Invalid PROXY header \"{line}\""),
+    }
+}
+
+fn read_v2(reader: &mut impl Read) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header)?;
+    let [version_command, family, len @ ..] = header;
+    let mut data = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut data)?;
+    if version_command >> 4 != 2 {
+        bail!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    // Local connections of the proxy itself and anything but TCP carry no client address
+    let proxied = version_command & 0x0f == 1;
+    let addrs = match family {
+        0x11 if proxied && data.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    data[at],
+                    data[at + 1],
+                    data[at + 2],
+                    data[at + 3],
+                ))
+            };
+            let port = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+            Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            ))
+        }
+        0x21 if proxied && data.len() >= 36 => {
+            let ip = |at: usize| {
+                IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(&data[at..at + 16]).expect("16 bytes"),
+                ))
+            };
+            let port = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+            Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            ))
+        }
+        _ => None,
+    };
+    Ok(addrs)
+}
+
+/// Both addresses as IPv4 if possible (dual stack sockets report IPv4-mapped addresses),
+/// otherwise both as IPv6
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let (source, destination) = (canonical(source), canonical(destination));
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    let ipv6 = |addr: SocketAddr| SocketAddr::new(IpAddr::V6(to_ipv6(addr.ip())), addr.port());
+    (ipv6(source), ipv6(destination))
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn write_headers() {
+        let client: SocketAddr = "[::ffff:203.0.113.7]:51234".parse().unwrap();
+        let proxy: SocketAddr = "[::ffff:192.0.2.1]:25565".parse().unwrap();
+        assert_eq!(
+            header(ProxyProtocolVersion::V1, Some((client, proxy))),
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 25565\r\n"
+        );
+        assert_eq!(header(ProxyProtocolVersion::V1, None), b"PROXY UNKNOWN\r\n");
+
+        let v2 = header(ProxyProtocolVersion::V2, Some((client, proxy)));
+        assert_eq!(v2[12..16], [0x21, 0x11, 0, 12]);
+        assert_eq!(
+            v2[16..],
+            [203, 0, 113, 7, 192, 0, 2, 1, 0xc8, 0x22, 0x63, 0xdd]
+        );
+
+        let v6_client: SocketAddr = "[2001:db8::7]:51234".parse().unwrap();
+        let v1 = header(ProxyProtocolVersion::V1, Some((v6_client, proxy)));
+        assert_eq!(
+            v1,
+            b"PROXY TCP6 2001:db8::7 ::ffff:192.0.2.1 51234 25565\r\n"
+        );
+        let v2 = header(ProxyProtocolVersion::V2, Some((v6_client, proxy)));
+        assert_eq!(v2[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(v2.len(), 16 + 36);
+
+        assert_eq!(
+            header(ProxyProtocolVersion::V2, None)[12..],
+            [0x20, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn read_written_headers() {
+        let client: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let proxy: SocketAddr = "192.0.2.1:25565".parse().unwrap();
+        let v6_client: SocketAddr = "[2001:db8::7]:51234".parse().unwrap();
+        let v6_proxy: SocketAddr = "[2001:db8::1]:25565".parse().unwrap();
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for addrs in [Some((client, proxy)), Some((v6_client, v6_proxy)), None] {
+                let mut data = header(version, addrs);
+                data.extend_from_slice(b"handshake");
+                let mut reader = data.as_slice();
+                assert_eq!(read_header(&mut reader).unwrap(), addrs);
+                assert_eq!(reader, b"handshake");
+            }
+        }
+        assert!(read_header(&mut &b"\x10\x00\xff\x05\tlocalhost\x63\xdd\x02"[..]).is_err());
+        assert!(read_header(&mut &b"PROXY TCP4 1.2.3.4\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn match_cidrs() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let cidr = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("192.0.2.1")));
+        assert!(Cidr::parse("2001:db8::/32")
+            .unwrap()
+            .contains(ip("2001:db8::7")));
+        assert!(!Cidr::parse("2001:db8::/32")
+            .unwrap()
+            .contains(ip("10.1.2.3")));
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains(ip("192.0.2.1")));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("example.org").is_err());
+    }
+}
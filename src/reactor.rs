@@ -0,0 +1,1111 @@
+use crate::backends::BackendLease;
+use crate::coalescing::{self, Coalescing};
+use crate::splice::KernelPipe;
+use crate::stats::{self, ConnectionStats, StatsRecorder};
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use polling::{Event, Events, Poller};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::Span;
+
+/// A client that is logged in and connected to the target. From here on all
+/// data is just forwarded between both sockets.
+pub struct Connection {
+    pub client: TcpStream,
+    pub target: TcpStream,
+    /// Kept alive for as long as the connection is open, which marks the source ip as in use
+    #[allow(dead_code)]
+    pub source_ip: Option<Arc<IpAddr>>,
+    /// Kept alive for as long as the connection is open, which counts it for the backend
+    #[allow(dead_code)]
+    pub backend: BackendLease,
+    /// When to forward data from client to target
+    pub upstream_flush: FlushPolicy,
+    /// When to forward data from target to client
+    pub downstream_flush: FlushPolicy,
+}
+
+/// Decides when collected data gets forwarded
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    /// Max time the oldest pending byte may be held back. None forwards immediately.
+    pub deadline: Option<Duration>,
+    /// Forward early once this many bytes are pending (0 to only use the deadline)
+    pub flush_bytes: usize,
+    /// Only hold data back while the traffic exceeds this
+    pub burst: Option<BurstTrigger>,
+}
+
+/// Rates at which a direction counts as flooded
+#[derive(Debug, Clone, Copy)]
+pub struct BurstTrigger {
+    pub bytes_per_sec: Option<u64>,
+    pub reads_per_sec: Option<u64>,
+    /// How long the rates need to stay below the thresholds to stop holding data back
+    pub cooldown: Duration,
+}
+
+/// Measures the traffic of one direction for BurstTrigger
+#[derive(Default)]
+struct BurstMeter {
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    window_reads: u64,
+    /// When the thresholds were exceeded last. Set while bursting.
+    exceeded_at: Option<Instant>,
+}
+
+impl BurstMeter {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn record_read(&mut self, bytes: usize) {
+        self.window_bytes += bytes as u64;
+        self.window_reads += 1;
+    }
+
+    /// Check the rates and switch between bursting or not. Returns true while bursting.
+    fn update(&mut self, trigger: BurstTrigger, now: Instant, source_name: &str) -> bool {
+        let window_start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(window_start).max(Self::WINDOW);
+        let bytes_per_sec = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+        let reads_per_sec = (self.window_reads as f64 / elapsed.as_secs_f64()) as u64;
+        let exceeded = trigger.bytes_per_sec.is_some_and(|max| bytes_per_sec > max)
+            || trigger.reads_per_sec.is_some_and(|max| reads_per_sec > max);
+        if exceeded {
+            if self.exceeded_at.is_none() {
+                info!(
+                    "Traffic from {source_name} exceeds burst threshold ({bytes_per_sec} bytes/s, {reads_per_sec} reads/s). Holding back data now."
+                );
+            }
+            self.exceeded_at = Some(now);
+        } else if self
+            .exceeded_at
+            .is_some_and(|exceeded_at| now.duration_since(exceeded_at) >= trigger.cooldown)
+        {
+            info!(
+                "Traffic from {source_name} stayed below burst threshold for {}s. Forwarding immediately again.",
+                trigger.cooldown.as_secs()
+            );
+            self.exceeded_at = None;
+        }
+
+        if now.duration_since(window_start) >= Self::WINDOW {
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+            self.window_reads = 0;
+        }
+        self.exceeded_at.is_some()
+    }
+}
+
+/// Settings shared by all forwarded connections
+#[derive(Debug, Clone, Copy)]
+pub struct ReactorConfig {
+    pub workers: usize,
+    pub verbose: bool,
+    /// Max bytes held per direction. Reading from the source pauses while reached.
+    pub max_buffered: usize,
+    /// How long a direction may stay at max_buffered before the connection gets kicked
+    pub max_buffered_timeout: Duration,
+    /// How long to wait for the other direction to finish after one side closed
+    pub linger_timeout: Duration,
+    /// Close connections without any data in either direction for this long
+    pub idle_timeout: Option<Duration>,
+    pub coalescing: Coalescing,
+    /// Used for Coalescing::NotsentLowat
+    pub notsent_lowat: u32,
+    /// Move data with splice(2) instead of copying it, if a connection doesn't batch anything
+    pub splice: bool,
+}
+
+/// Owns all forwarded connections and handles them on a few worker threads
+/// using a shared poller per worker (instead of one thread per connection).
+pub struct Reactor {
+    config: ReactorConfig,
+    workers: Vec<WorkerHandle>,
+    next_worker: AtomicUsize,
+}
+
+struct WorkerHandle {
+    poller: Arc<Poller>,
+    incoming: Arc<Mutex<Vec<Pair>>>,
+}
+
+impl Reactor {
+    pub fn start(config: ReactorConfig) -> Result<Self> {
+        let mut workers = Vec::with_capacity(config.workers.max(1));
+        for index in 0..config.workers.max(1) {
+            let poller = Arc::new(Poller::new().context("Create poller")?);
+            let incoming: Arc<Mutex<Vec<Pair>>> = Default::default();
+            let worker = Worker {
+                poller: poller.clone(),
+                incoming: incoming.clone(),
+                verbose: config.verbose,
+                pairs: Vec::new(),
+                free_ids: Vec::new(),
+                timers: BinaryHeap::new(),
+                buf: vec![0u8; 4096 * 16],
+            };
+            std::thread::Builder::new()
+                .name(format!("reactor-{index}"))
+                .spawn(move || worker.run())
+                .context("Spawn reactor worker")?;
+            workers.push(WorkerHandle { poller, incoming });
+        }
+        Ok(Self {
+            config,
+            workers,
+            next_worker: AtomicUsize::new(0),
+        })
+    }
+
+    /// Hand over a connection to one of the workers. The span is entered whenever the
+    /// connection is handled and the final result is logged relative to `started`.
+    pub fn add(&self, connection: Connection, span: Span, started: Instant) -> Result<()> {
+        connection.client.set_nonblocking(true)?;
+        connection.target.set_nonblocking(true)?;
+        for socket in [&connection.client, &connection.target] {
+            self.config
+                .coalescing
+                .apply(socket, self.config.notsent_lowat)
+                .context("Apply coalescing strategy")?;
+        }
+        let pair = Pair::new(connection, span, started, self.config)?;
+
+        let index = self.next_worker.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        let worker = &self.workers[index];
+        worker.incoming.lock().expect("Lock incoming").push(pair);
+        worker.poller.notify().context("Notify reactor worker")?;
+        Ok(())
+    }
+}
+
+/// Which socket of a pair an event belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client,
+    Target,
+}
+
+impl Side {
+    fn from_key(key: usize) -> (usize, Self) {
+        (
+            key / 2,
+            if key.is_multiple_of(2) {
+                Side::Client
+            } else {
+                Side::Target
+            },
+        )
+    }
+
+    fn key(self, id: usize) -> usize {
+        id * 2 + if self == Side::Client { 0 } else { 1 }
+    }
+}
+
+/// Data read from one socket which still needs to be written to the other one
+#[derive(Default)]
+struct Pipe {
+    buf: Vec<u8>,
+    /// Used instead of buf to splice data without copying it (see ReactorConfig::splice)
+    kernel: Option<KernelPipe>,
+    /// When the collected data is due to be sent
+    flush_at: Option<Instant>,
+    /// When the last flush started
+    last_flush: Option<Instant>,
+    /// Set once the data is due. Stays set until everything was written.
+    flushing: bool,
+    /// Since when the buffer is full without the sink accepting any of it
+    full_since: Option<Instant>,
+    /// When data was last received from the source
+    last_read: Option<Instant>,
+    /// The source closed its sending side. Remaining data still gets written.
+    eof: bool,
+    /// Everything was written and the sending side of the sink got shut down as well
+    shut_down: bool,
+    /// The sink went away after sending its own EOF, so the rest can't be delivered anymore
+    sink_gone: bool,
+    burst: BurstMeter,
+    stats: StatsRecorder,
+    /// When data corked in the sink should be pushed out (Coalescing::Cork)
+    uncork_at: Option<Instant>,
+}
+
+impl Pipe {
+    /// Read everything currently available (up to the limit). Returns false if the source reached EOF.
+    fn read_from(
+        &mut self,
+        source: &mut TcpStream,
+        buf: &mut [u8],
+        limit: usize,
+        now: Instant,
+    ) -> std::io::Result<bool> {
+        let limit = self.capped(limit);
+        while self.pending() < limit {
+            let max_read = limit - self.pending();
+            let result = match &mut self.kernel {
+                Some(kernel) => kernel.fill_from(source, max_read),
+                None => {
+                    let max_read = max_read.min(buf.len());
+                    let result = source.read(&mut buf[..max_read]);
+                    if let Ok(read) = result {
+                        self.buf.extend_from_slice(&buf[..read]);
+                    }
+                    result
+                }
+            };
+            match result {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(false);
+                }
+                Ok(read) => {
+                    self.last_read = Some(now);
+                    self.burst.record_read(read);
+                    self.stats.record_read(read);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if self.eof && is_disconnect(&err) => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        // Leave the rest in the socket until the sink caught up
+        self.full_since.get_or_insert(now);
+        Ok(true)
+    }
+
+    /// Mark the data as due if the policy says so. Otherwise remember when it will be.
+    fn schedule(&mut self, policy: FlushPolicy, limit: usize, now: Instant, source_name: &str) {
+        let bursting = match policy.burst {
+            Some(trigger) => self.burst.update(trigger, now, source_name),
+            None => true,
+        };
+        if self.pending() == 0 || self.flushing {
+            return;
+        }
+        let Some(deadline) = policy.deadline.filter(|_| bursting) else {
+            return self.start_flush(now);
+        };
+        if self.pending() >= self.capped(limit) || self.eof {
+            return self.start_flush(now);
+        }
+        if policy.flush_bytes > 0 && self.pending() >= policy.flush_bytes {
+            return self.start_flush(now);
+        }
+        // Nothing was sent for a while, so this isn't a flood worth batching
+        if self
+            .last_flush
+            .is_none_or(|last_flush| now.duration_since(last_flush) >= deadline)
+        {
+            return self.start_flush(now);
+        }
+
+        let flush_at = *self.flush_at.get_or_insert(now + deadline);
+        if flush_at <= now {
+            self.start_flush(now);
+        }
+    }
+
+    fn start_flush(&mut self, now: Instant) {
+        self.stats.record_flush();
+        self.flushing = true;
+        self.last_flush = Some(now);
+    }
+
+    /// Write as much as the sink accepts if the data is due. Returns the amount of written bytes.
+    /// `sink_eof` tells whether the sink already sent its EOF, so it may disconnect any time.
+    fn write_to(&mut self, sink: &mut TcpStream, sink_eof: bool) -> std::io::Result<usize> {
+        if !self.flushing && self.pending() > 0 {
+            return Ok(0);
+        }
+        let mut total_written = 0;
+        while self.pending() > 0 {
+            let result = match &mut self.kernel {
+                Some(kernel) => kernel.drain_to(sink),
+                None => sink.write(&self.buf).inspect(|&written| {
+                    self.buf.drain(..written);
+                }),
+            };
+            match result {
+                Ok(written) => {
+                    total_written += written;
+                    self.stats.record_write(written);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if sink_eof && is_disconnect(&err) => {
+                    self.sink_gone = true;
+                    return Ok(total_written);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if total_written > 0 {
+            self.full_since = None;
+        }
+        if self.pending() == 0 {
+            self.flushing = false;
+            self.flush_at = None;
+            if self.eof && !self.shut_down {
+                // Propagate the FIN to the other side
+                match sink.shutdown(Shutdown::Write) {
+                    Err(err) if err.kind() != ErrorKind::NotConnected => return Err(err),
+                    _ => self.shut_down = true,
+                }
+            }
+        }
+        Ok(total_written)
+    }
+
+    /// Bytes read, but not yet written
+    fn pending(&self) -> usize {
+        self.buf.len() + self.kernel.as_ref().map_or(0, KernelPipe::len)
+    }
+
+    /// A kernel pipe can't hold more than its capacity
+    fn capped(&self, limit: usize) -> usize {
+        match &self.kernel {
+            Some(kernel) => limit.min(kernel.capacity()),
+            None => limit,
+        }
+    }
+
+    /// Push out data held back by TCP_CORK if it was corked long enough
+    fn uncork_if_due(&mut self, sink: &TcpStream, now: Instant) -> Result<()> {
+        if self.uncork_at.is_some_and(|uncork_at| uncork_at <= now) {
+            self.uncork_at = None;
+            coalescing::uncork(sink).context("Uncork")?;
+        }
+        Ok(())
+    }
+
+    fn wants_read(&self, limit: usize) -> bool {
+        !self.eof && self.pending() < self.capped(limit)
+    }
+
+    fn wants_write(&self) -> bool {
+        self.flushing && self.pending() > 0
+    }
+
+    /// Fails if the sink did not accept anything for too long while the buffer was full
+    fn check_stalled(&self, timeout: Duration, now: Instant, sink_name: &str) -> Result<()> {
+        if let Some(full_since) = self.full_since {
+            if now.duration_since(full_since) >= timeout {
+                bail!(
+                    "Kicked, because {} bytes were buffered for the {sink_name} for over {}s without it accepting any of them!",
+                    self.pending(),
+                    timeout.as_secs()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn pending_timer(&self, policy: FlushPolicy, stall_timeout: Duration) -> Option<Instant> {
+        let flush_at = if self.flushing { None } else { self.flush_at };
+        let stalled_at = self.full_since.map(|full_since| full_since + stall_timeout);
+        let cooled_down_at = policy
+            .burst
+            .zip(self.burst.exceeded_at)
+            .map(|(trigger, exceeded_at)| exceeded_at + trigger.cooldown);
+        [flush_at, stalled_at, cooled_down_at, self.uncork_at]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+/// Whether the error only means that the peer closed the connection
+fn is_disconnect(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::NotConnected
+    )
+}
+
+/// Forwarding state of one connection. Upstream is client -> target, downstream target -> client.
+struct Pair {
+    span: Span,
+    started: Instant,
+    /// When the connection was handed to the reactor
+    forwarding_since: Instant,
+    config: ReactorConfig,
+    connection: Connection,
+    upstream: Pipe,
+    downstream: Pipe,
+    /// Earliest deadline with an entry in the timer queue (might be before the actual next one)
+    armed_timer: Option<Instant>,
+    /// Set once one side closed. The connection gets torn down if not finished until then.
+    linger_until: Option<Instant>,
+}
+
+impl Pair {
+    fn new(
+        connection: Connection,
+        span: Span,
+        started: Instant,
+        config: ReactorConfig,
+    ) -> Result<Self> {
+        let mut pair = Pair {
+            span,
+            started,
+            forwarding_since: Instant::now(),
+            config,
+            upstream: Pipe {
+                stats: StatsRecorder::new(&stats::UPSTREAM_TOTALS),
+                ..Default::default()
+            },
+            downstream: Pipe {
+                stats: StatsRecorder::new(&stats::DOWNSTREAM_TOTALS),
+                ..Default::default()
+            },
+            armed_timer: None,
+            linger_until: None,
+            connection,
+        };
+        if config.splice && pair.forwards_immediately() {
+            pair.upstream.kernel = Some(KernelPipe::new(config.max_buffered)?);
+            pair.downstream.kernel = Some(KernelPipe::new(config.max_buffered)?);
+        }
+        Ok(pair)
+    }
+
+    /// Returns Ok(false) once the connection should be closed
+    fn on_event(&mut self, side: Side, event: Event, buf: &mut [u8]) -> Result<bool> {
+        let now = Instant::now();
+        let limit = self.config.max_buffered;
+        let (pipe, source, source_name) = match side {
+            Side::Client => (&mut self.upstream, &mut self.connection.client, "client"),
+            Side::Target => (&mut self.downstream, &mut self.connection.target, "target"),
+        };
+        // A closed socket keeps being reported as readable (even with only write interest), so
+        // only read until the EOF
+        if event.readable
+            && !pipe.eof
+            && !pipe
+                .read_from(source, buf, limit, now)
+                .with_context(|| format!("Read {source_name}"))?
+        {
+            info!("Connection terminated by {source_name}!");
+        }
+        self.progress(now)
+    }
+
+    fn on_timer(&mut self, now: Instant) -> Result<bool> {
+        self.progress(now)?;
+
+        let timeout = self.config.max_buffered_timeout;
+        self.upstream.check_stalled(timeout, now, "target")?;
+        self.downstream.check_stalled(timeout, now, "client")?;
+        if self
+            .linger_until
+            .is_some_and(|linger_until| linger_until <= now)
+        {
+            info!(
+                "Other side did not finish within {}s after the connection got terminated. Closing.",
+                self.config.linger_timeout.as_secs()
+            );
+            return Ok(false);
+        }
+        if self.idle_deadline().is_some_and(|deadline| deadline <= now) {
+            bail!(
+                "Kicked, because no data was sent in either direction for {}s",
+                self.config.idle_timeout.unwrap_or_default().as_secs()
+            );
+        }
+        Ok(true)
+    }
+
+    /// Send whatever is due and close once both directions are done
+    fn progress(&mut self, now: Instant) -> Result<bool> {
+        let limit = self.config.max_buffered;
+        let (upstream_flush, downstream_flush) = self.userspace_flush_policies();
+        self.upstream.schedule(upstream_flush, limit, now, "client");
+        self.downstream
+            .schedule(downstream_flush, limit, now, "target");
+        self.flush(now)?;
+        self.upstream.uncork_if_due(&self.connection.target, now)?;
+        self.downstream
+            .uncork_if_due(&self.connection.client, now)?;
+
+        if (self.upstream.eof || self.downstream.eof) && self.linger_until.is_none() {
+            self.linger_until = Some(now + self.config.linger_timeout);
+        }
+        if self.upstream.sink_gone || self.downstream.sink_gone {
+            info!("Other side disconnected before everything was forwarded. Closing.");
+            return Ok(false);
+        }
+        Ok(!(self.upstream.shut_down && self.downstream.shut_down))
+    }
+
+    fn flush(&mut self, now: Instant) -> Result<()> {
+        let upstream_written = self
+            .upstream
+            .write_to(&mut self.connection.target, self.downstream.eof)
+            .context("Write to target")?;
+        let downstream_written = self
+            .downstream
+            .write_to(&mut self.connection.client, self.upstream.eof)
+            .context("Write to client")?;
+
+        if self.config.coalescing == Coalescing::Cork {
+            // The kernel holds the data back now, so uncork after the delay
+            for (pipe, written, policy) in [
+                (
+                    &mut self.upstream,
+                    upstream_written,
+                    self.connection.upstream_flush,
+                ),
+                (
+                    &mut self.downstream,
+                    downstream_written,
+                    self.connection.downstream_flush,
+                ),
+            ] {
+                if written > 0 && pipe.uncork_at.is_none() {
+                    pipe.uncork_at = Some(now + policy.deadline.unwrap_or_default());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// When the connection counts as idle, unless something gets received until then
+    fn idle_deadline(&self) -> Option<Instant> {
+        let timeout = self.config.idle_timeout?;
+        let last_activity = [self.upstream.last_read, self.downstream.last_read]
+            .into_iter()
+            .flatten()
+            .fold(self.forwarding_since, Instant::max);
+        Some(last_activity + timeout)
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            upstream: self.upstream.stats.stats,
+            downstream: self.downstream.stats.stats,
+        }
+    }
+
+    /// Whether data never needs to be held back in the proxy
+    fn forwards_immediately(&self) -> bool {
+        let (upstream_flush, downstream_flush) = self.userspace_flush_policies();
+        [upstream_flush, downstream_flush]
+            .iter()
+            .all(|policy| policy.deadline.is_none() && policy.burst.is_none())
+    }
+
+    /// With kernel-side coalescing, the proxy itself forwards everything immediately
+    fn userspace_flush_policies(&self) -> (FlushPolicy, FlushPolicy) {
+        if self.config.coalescing == Coalescing::Userspace {
+            return (
+                self.connection.upstream_flush,
+                self.connection.downstream_flush,
+            );
+        }
+        let immediate = FlushPolicy {
+            deadline: None,
+            flush_bytes: 0,
+            burst: None,
+        };
+        (immediate, immediate)
+    }
+
+    fn interest(&self, id: usize, side: Side) -> Event {
+        let limit = self.config.max_buffered;
+        let (readable, writable) = match side {
+            Side::Client => (
+                self.upstream.wants_read(limit),
+                self.downstream.wants_write(),
+            ),
+            Side::Target => (
+                self.downstream.wants_read(limit),
+                self.upstream.wants_write(),
+            ),
+        };
+        Event::new(side.key(id), readable, writable)
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        let stall_timeout = self.config.max_buffered_timeout;
+        let (upstream_flush, downstream_flush) = self.userspace_flush_policies();
+        [
+            self.upstream.pending_timer(upstream_flush, stall_timeout),
+            self.downstream
+                .pending_timer(downstream_flush, stall_timeout),
+            self.linger_until,
+            self.idle_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+}
+
+struct Worker {
+    poller: Arc<Poller>,
+    incoming: Arc<Mutex<Vec<Pair>>>,
+    verbose: bool,
+    pairs: Vec<Option<Pair>>,
+    free_ids: Vec<usize>,
+    timers: BinaryHeap<Reverse<(Instant, usize)>>,
+    buf: Vec<u8>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut events = Events::new();
+        loop {
+            let timeout = self
+                .timers
+                .peek()
+                .map(|Reverse((at, _))| at.saturating_duration_since(Instant::now()));
+            events.clear();
+            if let Err(err) = self.poller.wait(&mut events, timeout) {
+                if err.kind() != ErrorKind::Interrupted {
+                    error!("Failed to wait for events: {err}");
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                continue;
+            }
+
+            let incoming = std::mem::take(&mut *self.incoming.lock().expect("Lock incoming"));
+            for pair in incoming {
+                self.insert(pair);
+            }
+
+            for event in events.iter() {
+                let (id, side) = Side::from_key(event.key);
+                self.process(id, |pair, buf| pair.on_event(side, event, buf));
+            }
+
+            self.fire_timers(Instant::now());
+        }
+    }
+
+    fn fire_timers(&mut self, now: Instant) {
+        while let Some(Reverse((at, id))) = self.timers.peek().copied() {
+            if at > now {
+                break;
+            }
+            self.timers.pop();
+            let due = match self.pairs.get_mut(id) {
+                Some(Some(pair)) if pair.armed_timer == Some(at) => {
+                    pair.armed_timer = None;
+                    true
+                }
+                _ => false, // Replaced by an earlier one or already closed
+            };
+            if due {
+                self.process(id, |pair, _| pair.on_timer(now));
+            }
+        }
+    }
+
+    fn insert(&mut self, pair: Pair) {
+        let id = match self.free_ids.pop() {
+            Some(id) => id,
+            None => {
+                self.pairs.push(None);
+                self.pairs.len() - 1
+            }
+        };
+        let _entered = pair.span.clone().entered();
+        // SAFETY: Both sockets are deleted from the poller in close() before being dropped
+        let result = unsafe {
+            self.poller
+                .add(&pair.connection.client, pair.interest(id, Side::Client))
+                .and_then(|_| {
+                    self.poller
+                        .add(&pair.connection.target, pair.interest(id, Side::Target))
+                })
+        };
+        self.pairs[id] = Some(pair);
+        // Arms the timers, so even connections that never send anything time out
+        let result = result
+            .context("Register connection")
+            .and_then(|_| self.rearm(id));
+        if let Err(err) = result {
+            self.close(id, Err(err));
+        }
+    }
+
+    fn process(&mut self, id: usize, handler: impl FnOnce(&mut Pair, &mut [u8]) -> Result<bool>) {
+        let Some(Some(pair)) = self.pairs.get_mut(id) else {
+            return; // Already closed
+        };
+        let _entered = pair.span.clone().entered();
+        match handler(pair, &mut self.buf) {
+            Ok(true) => {
+                if let Err(err) = self.rearm(id) {
+                    self.close(id, Err(err));
+                }
+            }
+            Ok(false) => self.close(id, Ok(())),
+            Err(err) => self.close(id, Err(err)),
+        }
+    }
+
+    fn rearm(&mut self, id: usize) -> Result<()> {
+        let Some(Some(pair)) = self.pairs.get_mut(id) else {
+            return Ok(());
+        };
+        self.poller
+            .modify(&pair.connection.client, pair.interest(id, Side::Client))
+            .context("Update client interest")?;
+        self.poller
+            .modify(&pair.connection.target, pair.interest(id, Side::Target))
+            .context("Update target interest")?;
+        // Only queue deadlines earlier than the armed one. Later ones (like the idle deadline,
+        // which moves with every read) get armed once the armed one fired with nothing due, so
+        // the queue doesn't fill up with outdated entries.
+        if let Some(at) = pair.next_timer() {
+            if pair.armed_timer.is_none_or(|armed| at < armed) {
+                pair.armed_timer = Some(at);
+                self.timers.push(Reverse((at, id)));
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, id: usize, result: Result<()>) {
+        let Some(pair) = self.pairs.get_mut(id).and_then(Option::take) else {
+            return;
+        };
+        self.free_ids.push(id);
+        let _ = self.poller.delete(&pair.connection.client);
+        let _ = self.poller.delete(&pair.connection.target);
+
+        let stats = pair.stats();
+        let _entered = pair.span.entered();
+        crate::finish_connection(pair.started, self.verbose, result, Some(stats));
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::backends::{BackendAddr, Backends, Strategy};
+    use std::net::TcpListener;
+
+    /// Both ends of a connection over loopback
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connected = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        (connected, accepted)
+    }
+
+    /// Read from the source until it reports EOF
+    fn read_until_eof(pipe: &mut Pipe, source: &mut TcpStream) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = [0u8; 1024];
+        while pipe
+            .read_from(source, &mut buf, 1024, Instant::now())
+            .unwrap()
+        {
+            assert!(Instant::now() < deadline, "No EOF received");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn drain_and_propagate_eof() {
+        let policy = FlushPolicy {
+            deadline: Some(Duration::from_millis(50)),
+            flush_bytes: 0,
+            burst: None,
+        };
+        for data in [&b"kick"[..], b""] {
+            let (mut server, mut source) = socket_pair();
+            let (mut sink, mut client) = socket_pair();
+            source.set_nonblocking(true).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            // Like a server sending a kick packet and closing right after
+            server.write_all(data).unwrap();
+            server.shutdown(Shutdown::Write).unwrap();
+            let mut pipe = Pipe {
+                last_flush: Some(Instant::now()),
+                ..Default::default()
+            };
+            read_until_eof(&mut pipe, &mut source);
+            assert_eq!(pipe.pending(), data.len());
+
+            // Data still pending gets flushed (without waiting for the deadline) before the FIN
+            pipe.schedule(policy, 1024, Instant::now(), "target");
+            pipe.write_to(&mut sink, false).unwrap();
+            assert!(pipe.shut_down);
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            assert_eq!(received, data);
+        }
+    }
+
+    #[test]
+    fn pause_and_kick_when_full() {
+        let (mut server, mut source) = socket_pair();
+        source.set_nonblocking(true).unwrap();
+        server.write_all(&[0u8; 2048]).unwrap();
+
+        let mut pipe = Pipe::default();
+        let mut buf = [0u8; 512];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pipe.pending() < 1024 {
+            assert!(Instant::now() < deadline, "Buffer not filled");
+            assert!(pipe
+                .read_from(&mut source, &mut buf, 1024, Instant::now())
+                .unwrap());
+        }
+        // The rest stays in the socket until the sink accepts something
+        assert_eq!(pipe.pending(), 1024);
+        assert!(!pipe.wants_read(1024));
+        let full_since = pipe.full_since.expect("Full");
+
+        let timeout = Duration::from_secs(30);
+        let almost = full_since + timeout - Duration::from_millis(1);
+        pipe.check_stalled(timeout, almost, "target").unwrap();
+        let err = pipe
+            .check_stalled(timeout, full_since + timeout, "target")
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Kicked, because 1024 bytes"));
+    }
+
+    /// Pipe with data pending, which last flushed at `last_flush`
+    fn pipe_with(pending: usize, last_flush: Instant) -> Pipe {
+        Pipe {
+            buf: vec![0u8; pending],
+            last_flush: Some(last_flush),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn schedule_flushes() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let policy = FlushPolicy {
+            deadline: Some(Duration::from_millis(50)),
+            flush_bytes: 100,
+            burst: None,
+        };
+
+        // Held back until the deadline of the oldest byte
+        let mut pipe = pipe_with(10, start);
+        pipe.schedule(policy, 1000, ms(10), "client");
+        assert!(!pipe.flushing);
+        assert_eq!(pipe.flush_at, Some(ms(60)));
+        pipe.buf.extend_from_slice(&[0u8; 10]);
+        pipe.schedule(policy, 1000, ms(40), "client");
+        assert_eq!(pipe.flush_at, Some(ms(60)));
+        assert!(!pipe.flushing);
+        pipe.schedule(policy, 1000, ms(60), "client");
+        assert!(pipe.flushing);
+        assert_eq!(pipe.last_flush, Some(ms(60)));
+
+        // Early once flush_bytes or the buffer limit are reached
+        let mut pipe = pipe_with(100, start);
+        pipe.schedule(policy, 1000, ms(10), "client");
+        assert!(pipe.flushing);
+        let mut pipe = pipe_with(50, start);
+        pipe.schedule(policy, 50, ms(10), "client");
+        assert!(pipe.flushing);
+
+        // Immediately after a quiet period, so single packets don't get delayed
+        let mut pipe = pipe_with(10, start);
+        pipe.schedule(policy, 1000, ms(50), "client");
+        assert!(pipe.flushing);
+        let mut pipe = pipe_with(10, start);
+        pipe.last_flush = None;
+        pipe.schedule(policy, 1000, ms(0), "client");
+        assert!(pipe.flushing);
+
+        // Without deadline, everything is forwarded immediately
+        let mut pipe = pipe_with(1, start);
+        let immediate = FlushPolicy {
+            deadline: None,
+            ..policy
+        };
+        pipe.schedule(immediate, 1000, ms(1), "client");
+        assert!(pipe.flushing);
+
+        // Nothing to do without data
+        let mut pipe = pipe_with(0, start);
+        pipe.schedule(policy, 1000, ms(100), "client");
+        assert!(!pipe.flushing);
+        assert_eq!(pipe.flush_at, None);
+    }
+
+    #[test]
+    fn burst_enter_and_cool_down() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let trigger = BurstTrigger {
+            bytes_per_sec: Some(1000),
+            reads_per_sec: None,
+            cooldown: Duration::from_secs(2),
+        };
+
+        let mut meter = BurstMeter::default();
+        meter.record_read(500);
+        assert!(!meter.update(trigger, secs(0), "target"));
+        meter.record_read(5000);
+        assert!(meter.update(trigger, secs(1), "target"));
+        // Still held back during the cooldown, even though the rates dropped
+        assert!(meter.update(trigger, secs(2), "target"));
+        meter.record_read(5000);
+        assert!(meter.update(trigger, secs(3), "target"));
+        assert!(meter.update(trigger, secs(4), "target"));
+        assert!(!meter.update(trigger, secs(5), "target"));
+
+        let by_reads = BurstTrigger {
+            bytes_per_sec: None,
+            reads_per_sec: Some(10),
+            ..trigger
+        };
+        let mut meter = BurstMeter::default();
+        (0..20).for_each(|_| meter.record_read(1));
+        assert!(meter.update(by_reads, secs(0), "target"));
+    }
+
+    #[test]
+    fn schedule_only_batches_while_bursting() {
+        let start = Instant::now();
+        let policy = FlushPolicy {
+            deadline: Some(Duration::from_millis(50)),
+            flush_bytes: 0,
+            burst: Some(BurstTrigger {
+                bytes_per_sec: Some(1000),
+                reads_per_sec: None,
+                cooldown: Duration::from_secs(2),
+            }),
+        };
+
+        let mut pipe = pipe_with(10, start);
+        pipe.burst.record_read(10);
+        pipe.schedule(policy, 1 << 20, start + Duration::from_millis(1), "target");
+        assert!(pipe.flushing);
+
+        let mut pipe = pipe_with(10, start);
+        pipe.burst.record_read(5000);
+        pipe.schedule(policy, 1 << 20, start + Duration::from_millis(1), "target");
+        assert!(!pipe.flushing);
+        assert_eq!(pipe.flush_at, Some(start + Duration::from_millis(51)));
+    }
+
+    fn test_config() -> ReactorConfig {
+        ReactorConfig {
+            workers: 1,
+            verbose: false,
+            max_buffered: 1024,
+            max_buffered_timeout: Duration::from_secs(30),
+            linger_timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(300)),
+            coalescing: Coalescing::Userspace,
+            notsent_lowat: 0,
+            splice: false,
+        }
+    }
+
+    /// Pair forwarding immediately, with the remote ends of its client and target sockets
+    fn test_pair(config: ReactorConfig) -> (Pair, TcpStream, TcpStream) {
+        let immediate = FlushPolicy {
+            deadline: None,
+            flush_bytes: 0,
+            burst: None,
+        };
+        let (client, client_peer) = socket_pair();
+        let (target, target_peer) = socket_pair();
+        client.set_nonblocking(true).unwrap();
+        target.set_nonblocking(true).unwrap();
+        let backends = Backends::new(
+            vec![BackendAddr::parse("127.0.0.1").unwrap()],
+            Strategy::Failover,
+        );
+        let connection = Connection {
+            client,
+            target,
+            source_ip: None,
+            backend: BackendLease::new(backends.candidates().remove(0)),
+            upstream_flush: immediate,
+            downstream_flush: immediate,
+        };
+        let pair = Pair::new(connection, Span::none(), Instant::now(), config).unwrap();
+        (pair, client_peer, target_peer)
+    }
+
+    #[test]
+    fn timers_do_not_pile_up() {
+        let (pair, _client_peer, _target_peer) = test_pair(test_config());
+        let mut worker = Worker {
+            poller: Arc::new(Poller::new().unwrap()),
+            incoming: Default::default(),
+            verbose: false,
+            pairs: Vec::new(),
+            free_ids: Vec::new(),
+            timers: BinaryHeap::new(),
+            buf: vec![0u8; 1024],
+        };
+        worker.insert(pair);
+        let armed = worker.pairs[0].as_ref().unwrap().armed_timer.unwrap();
+
+        // Every read moves the idle deadline back
+        let start = Instant::now();
+        let last_read = start + Duration::from_millis(1000);
+        for ms in 1..=1000 {
+            let pair = worker.pairs[0].as_mut().unwrap();
+            pair.upstream.last_read = Some(start + Duration::from_millis(ms));
+            worker.rearm(0).unwrap();
+        }
+        assert_eq!(worker.timers.len(), 1);
+
+        // Once the outdated entry fires, the actual deadline gets armed
+        worker.fire_timers(armed);
+        let pair = worker.pairs[0].as_ref().expect("Not idle yet");
+        assert_eq!(pair.armed_timer, Some(last_read + Duration::from_secs(300)));
+        assert_eq!(worker.timers.len(), 1);
+
+        worker.fire_timers(last_read + Duration::from_secs(300));
+        assert!(worker.pairs[0].is_none());
+    }
+
+    #[test]
+    fn client_disconnect_is_no_failure() {
+        for drain in [false, true] {
+            let (mut pair, mut client, mut target) = test_pair(test_config());
+            let mut buf = [0u8; 1024];
+            let mut forward = |pair: &mut Pair| -> Result<bool> {
+                pair.on_event(Side::Client, Event::readable(0), &mut buf)?;
+                pair.on_event(Side::Target, Event::readable(1), &mut buf)
+            };
+            if drain {
+                target.write_all(b"status").unwrap();
+                client.set_nonblocking(true).unwrap();
+                let mut received = Vec::new();
+                while received.len() < 6 {
+                    forward(&mut pair).unwrap();
+                    let _ = client.read_to_end(&mut received);
+                }
+            }
+            // Closing while the target keeps sending, which the client answers with a reset
+            drop(client);
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while forward(&mut pair).expect("Normal disconnect") {
+                assert!(Instant::now() < deadline, "Connection not closed");
+                target.write_all(&[0u8; 512]).unwrap();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert!(pair.upstream.eof);
+        }
+    }
+}
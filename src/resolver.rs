@@ -0,0 +1,420 @@
+use crate::dns;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 25565;
+
+/// Cached targets which were not used for this long stop getting refreshed and are dropped
+const UNUSED_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// Where to actually connect to for a target host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedTarget {
+    /// Port to connect to (from the SRV record if no port was given)
+    pub port: u16,
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// Target host and explicitly given port
+type Key = (String, Option<u16>);
+
+struct Entry {
+    target: ResolvedTarget,
+    /// When the target was last resolved successfully
+    resolved_at: Instant,
+    last_used: Instant,
+}
+
+/// Lookup which is still running. Others needing the same target wait for its result.
+#[derive(Default)]
+struct InFlight {
+    /// Set once done (the error as text, as it's shared)
+    result: Mutex<Option<Result<ResolvedTarget, String>>>,
+    done: Condvar,
+}
+
+impl InFlight {
+    fn wait(&self) -> Result<ResolvedTarget> {
+        let result = self.result.lock().expect("Lock lookup result");
+        let result = self
+            .done
+            .wait_while(result, |result| result.is_none())
+            .expect("Wait for lookup result");
+        result
+            .clone()
+            .expect("Lookup done")
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn finish(&self, result: &Result<ResolvedTarget>) {
+        let shared = match result {
+            Ok(target) => Ok(target.clone()),
+            Err(err) => Err(format!("{err:#}")),
+        };
+        *self.result.lock().expect("Lock lookup result") = Some(shared);
+        self.done.notify_all();
+    }
+}
+
+/// Resolves targets (including SRV records) and caches the results, so only the first connection
+/// to a target has to wait for DNS. Cached targets get resolved again in the background. If that
+/// fails, the last known addresses keep getting used.
+pub struct Resolver {
+    /// None disables caching
+    ttl: Option<Duration>,
+    /// Query this server instead of using the system resolver
+    dns_server: Option<SocketAddr>,
+    /// Fixed addresses for (lowercase) hosts, which never get looked up via DNS
+    overrides: HashMap<String, Vec<IpAddr>>,
+    cache: Mutex<HashMap<Key, Entry>>,
+    /// Lookups of targets which were not cached yet, so a burst of connections only causes one
+    in_flight: Mutex<HashMap<Key, Arc<InFlight>>>,
+}
+
+impl Resolver {
+    pub fn new(
+        ttl: Option<Duration>,
+        dns_server: Option<SocketAddr>,
+        overrides: HashMap<String, Vec<IpAddr>>,
+    ) -> Self {
+        Self {
+            ttl,
+            dns_server,
+            overrides,
+            cache: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Refresh cached targets in the background, so they are never older than the ttl
+    pub fn start_refreshing(self: &Arc<Self>) -> Result<()> {
+        let Some(ttl) = self.ttl else {
+            return Ok(());
+        };
+        let resolver = self.clone();
+        std::thread::Builder::new()
+            .name("dns-refresh".to_owned())
+            .spawn(move || loop {
+                std::thread::sleep((ttl / 2).max(Duration::from_secs(1)));
+                resolver.refresh_due(ttl);
+            })
+            .context("Spawn DNS refresh thread")?;
+        Ok(())
+    }
+
+    /// Addresses for the host. Without a port, the SRV record is used (like vanilla clients do).
+    pub fn resolve(&self, host: &str, port: Option<u16>) -> Result<ResolvedTarget> {
+        let key = (host.to_owned(), port);
+        let now = Instant::now();
+        if self.ttl.is_some() {
+            let mut cache = self.cache.lock().expect("Lock DNS cache");
+            if let Some(entry) = cache.get_mut(&key) {
+                entry.last_used = now;
+                return Ok(entry.target.clone());
+            }
+        }
+
+        let in_flight = {
+            let mut lookups = self.in_flight.lock().expect("Lock DNS lookups");
+            if let Some(in_flight) = lookups.get(&key) {
+                let in_flight = in_flight.clone();
+                drop(lookups);
+                return in_flight.wait();
+            }
+            let in_flight = Arc::new(InFlight::default());
+            lookups.insert(key.clone(), in_flight.clone());
+            in_flight
+        };
+        let result = self.update(key.clone(), now);
+        self.in_flight
+            .lock()
+            .expect("Lock DNS lookups")
+            .remove(&key);
+        in_flight.finish(&result);
+        result
+    }
+
+    fn refresh_due(&self, ttl: Duration) {
+        let now = Instant::now();
+        let due: Vec<Key> = {
+            let mut cache = self.cache.lock().expect("Lock DNS cache");
+            cache.retain(|_, entry| entry.last_used + UNUSED_EXPIRY > now);
+            cache
+                .iter()
+                .filter(|(_, entry)| entry.resolved_at + ttl / 2 <= now)
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        for key in due {
+            // Errors are already logged and the previous result is kept
+            let _ = self.update(key, now);
+        }
+    }
+
+    /// Resolve without any caching. A failed SRV lookup is an error, unless `srv_fallback` is set,
+    /// in which case the host is used directly.
+    fn lookup(&self, host: &str, port: Option<u16>, srv_fallback: bool) -> Result<ResolvedTarget> {
+        let overridden = self.overrides.contains_key(&host.to_ascii_lowercase());
+        let (connect_host, port) = match port {
+            Some(port) => (host.to_owned(), port),
+            None if overridden => (host.to_owned(), DEFAULT_PORT),
+            None => match lookup_srv_target(host, self.dns_server) {
+                Ok(Some((srv_host, srv_port))) => (srv_host, srv_port),
+                Ok(None) => (host.to_owned(), DEFAULT_PORT),
+                Err(err) if srv_fallback => {
+                    error!("{err:#}. Connecting to {host} directly.");
+                    (host.to_owned(), DEFAULT_PORT)
+                }
+                Err(err) => return Err(err),
+            },
+        };
+
+        let addrs: Vec<SocketAddr> = self
+            .lookup_ips(&connect_host)?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        if addrs.is_empty() {
+            bail!("No address found for target host!");
+        }
+        Ok(ResolvedTarget { port, addrs })
+    }
+
+    /// Addresses from the overrides, the configured DNS server or the system resolver
+    fn lookup_ips(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Some(ips) = self.overrides.get(&host.to_ascii_lowercase()) {
+            return Ok(ips.clone());
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        } else if let Some(dns_server) = self.dns_server {
+            return dns::lookup_ips(dns_server, host, dns::TIMEOUT)
+                .with_context(|| format!("Look up {host} via {dns_server}"));
+        }
+
+        let addr_infos =
+            dns_lookup::getaddrinfo(Some(host), None, None).map_err(|e| anyhow!("{:?}", e))?;
+        let mut ips = Vec::new();
+        for addr_info in addr_infos {
+            let ip = addr_info?.sockaddr.ip();
+            // Same address gets returned for every socket type
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+        Ok(ips)
+    }
+
+    /// Resolve again and update the cache. Falls back to the cached target on errors.
+    fn update(&self, key: Key, now: Instant) -> Result<ResolvedTarget> {
+        let (host, port) = (&key.0, key.1);
+        let stale = self.ttl.and_then(|_| {
+            let cache = self.cache.lock().expect("Lock DNS cache");
+            cache.get(&key).map(|entry| entry.target.clone())
+        });
+        let result = self.lookup(host, port, stale.is_none());
+        if self.ttl.is_none() {
+            return result;
+        }
+
+        let mut cache = self.cache.lock().expect("Lock DNS cache");
+        match result {
+            Ok(target) => {
+                let last_used = cache.get(&key).map_or(now, |entry| entry.last_used);
+                let entry = Entry {
+                    target: target.clone(),
+                    resolved_at: now,
+                    last_used,
+                };
+                let previous = cache.insert(key.clone(), entry);
+                if previous.is_none_or(|previous| previous.target != target) {
+                    info!(
+                        "Resolved {host} to {} (port {})",
+                        format_addrs(&target.addrs),
+                        target.port
+                    );
+                }
+                Ok(target)
+            }
+            Err(err) => match cache.get(&key) {
+                Some(entry) => {
+                    error!(
+                        "Failed to resolve {host}, keep using {} from {}s ago: {err:#}",
+                        format_addrs(&entry.target.addrs),
+                        entry.resolved_at.elapsed().as_secs()
+                    );
+                    Ok(entry.target.clone())
+                }
+                None => Err(err),
+            },
+        }
+    }
+}
+
+/// Host and port from the _minecraft._tcp SRV record of the host (if there is one)
+fn lookup_srv_target(host: &str, dns_server: Option<SocketAddr>) -> Result<Option<(String, u16)>> {
+    if host.parse::<IpAddr>().is_ok() {
+        return Ok(None);
+    }
+    let name = format!("_minecraft._tcp.{host}");
+    let records = dns_server
+        .map_or_else(dns::system_server, Ok)
+        .and_then(|server| dns::lookup_srv(server, &name, dns::TIMEOUT))
+        .with_context(|| format!("Failed to look up SRV record {name}"))?;
+    Ok(records
+        .into_iter()
+        .next()
+        .map(|record| (record.target, record.port)))
+}
+
+/// Read a file in the format of /etc/hosts ("IP host [host...]" per line, # starts a comment)
+pub fn read_hosts_file(path: &str) -> Result<HashMap<String, Vec<IpAddr>>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Read {path}"))?;
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut parts = line.split_whitespace();
+        let Some(ip) = parts.next() else {
+            continue;
+        };
+        let ip = ip
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid IP in line {} of {path}", index + 1))?;
+        for host in parts {
+            hosts.entry(host.to_ascii_lowercase()).or_default().push(ip);
+        }
+    }
+    Ok(hosts)
+}
+
+fn format_addrs(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.ip().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Address of a DNS server which refuses every query
+    fn closed_dns_server() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    }
+
+    #[test]
+    fn serve_stale_on_error() {
+        let resolver = Resolver::new(
+            Some(Duration::from_secs(60)),
+            Some(closed_dns_server()),
+            HashMap::new(),
+        );
+        for port in [Some(25565), None] {
+            let key = ("play.example.org".to_owned(), port);
+            assert!(resolver.update(key.clone(), Instant::now()).is_err());
+
+            let stale = ResolvedTarget {
+                port: 25565,
+                addrs: vec!["192.0.2.1:25565".parse().unwrap()],
+            };
+            let resolved_at = Instant::now();
+            resolver.cache.lock().unwrap().insert(
+                key.clone(),
+                Entry {
+                    target: stale.clone(),
+                    resolved_at,
+                    last_used: resolved_at,
+                },
+            );
+            assert_eq!(resolver.update(key, Instant::now()).unwrap(), stale);
+            assert_eq!(resolver.resolve("play.example.org", port).unwrap(), stale);
+        }
+    }
+
+    #[test]
+    fn one_lookup_per_burst() {
+        static QUERIES: AtomicUsize = AtomicUsize::new(0);
+        let server = crate::dns::test::stand_in_server(|_, qtype| {
+            QUERIES.fetch_add(1, Ordering::Relaxed);
+            // Slow enough for all connections to miss the cache
+            std::thread::sleep(Duration::from_millis(100));
+            Some(crate::dns::test::address_records(
+                qtype,
+                &["10.0.0.1".parse().unwrap()],
+            ))
+        });
+        for ttl in [Some(Duration::from_secs(60)), None] {
+            QUERIES.store(0, Ordering::Relaxed);
+            let resolver = Arc::new(Resolver::new(ttl, Some(server), HashMap::new()));
+            let connections: Vec<_> = (0..8)
+                .map(|_| {
+                    let resolver = resolver.clone();
+                    std::thread::spawn(move || resolver.resolve("play.example.org", Some(25565)))
+                })
+                .collect();
+            for connection in connections {
+                assert_eq!(
+                    connection.join().unwrap().unwrap().addrs,
+                    ["10.0.0.1:25565".parse().unwrap()]
+                );
+            }
+            // A and AAAA
+            assert_eq!(QUERIES.load(Ordering::Relaxed), 2);
+            assert!(resolver.in_flight.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn overrides_skip_srv() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let overrides = HashMap::from([("play.example.org".to_owned(), vec![ip])]);
+        let resolver = Resolver::new(None, Some(closed_dns_server()), overrides);
+        assert_eq!(
+            resolver.lookup("Play.Example.org", None, false).unwrap(),
+            ResolvedTarget {
+                port: DEFAULT_PORT,
+                addrs: vec![SocketAddr::new(ip, DEFAULT_PORT)],
+            }
+        );
+        assert_eq!(
+            resolver
+                .lookup("play.example.org", Some(25566), false)
+                .unwrap()
+                .addrs,
+            [SocketAddr::new(ip, 25566)]
+        );
+        // Other hosts still need their SRV record
+        assert!(resolver.lookup("other.example.org", None, false).is_err());
+    }
+
+    #[test]
+    fn parse_hosts_file() {
+        let path =
+            std::env::temp_dir().join(format!("stupid-mc-proxy-hosts-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Pinned targets\n10.0.0.1 Play.Example.org mc.example.org # Main\n\n2001:db8::1 play.example.org\n",
+        )
+        .unwrap();
+        let hosts = read_hosts_file(path.to_str().unwrap()).unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(
+            hosts["play.example.org"],
+            [ip("10.0.0.1"), ip("2001:db8::1")]
+        );
+        assert_eq!(hosts["mc.example.org"], [ip("10.0.0.1")]);
+
+        std::fs::write(&path, "10.0.0.1 play.example.org\nexample.org 10.0.0.2\n").unwrap();
+        let err = read_hosts_file(path.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid IP in line 2"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+use crate::backends::{BackendAddr, Backends, Strategy};
+use clap::ValueEnum;
+use std::fmt::Display;
+use std::sync::Arc;
+
+/// Hostname a route applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    /// "*", any hostname
+    Any,
+    Exact(String),
+    /// "*.example.org", stored as ".example.org". Matches all subdomains, but not the domain itself.
+    Wildcard(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Self {
+        let pattern = normalize_host(pattern);
+        if pattern == "*" {
+            return HostPattern::Any;
+        }
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostPattern::Wildcard(suffix.to_owned()),
+            _ => HostPattern::Exact(pattern),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Exact(exact) => exact == host,
+            HostPattern::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+        }
+    }
+
+    /// Higher is more specific
+    fn specificity(&self) -> usize {
+        match self {
+            HostPattern::Any => 0,
+            HostPattern::Wildcard(suffix) => suffix.len(),
+            HostPattern::Exact(_) => usize::MAX,
+        }
+    }
+}
+
+impl Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostPattern::Any => write!(f, "*"),
+            HostPattern::Exact(exact) => write!(f, "{exact}"),
+            HostPattern::Wildcard(suffix) => write!(f, "*{suffix}"),
+        }
+    }
+}
+
+/// Protocol version for legacy pings, as their versions don't match the modern ones
+pub const LEGACY_PROTOCOL_VERSION: i32 = -1;
+
+/// Protocol versions from min to max (both inclusive)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolRange {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl ProtocolRange {
+    /// Parse "VERSION", "MIN-MAX", "MIN-" or "-MAX". Without a min, legacy pings match too.
+    pub fn parse(range: &str) -> Result<Self, String> {
+        let invalid = |_| format!("Expected protocol versions like 47-340, got \"{range}\"");
+        let (min, max) = range.split_once('-').unwrap_or((range, range));
+        Ok(Self {
+            min: match min {
+                "" => i32::MIN,
+                min => min.parse().map_err(invalid)?,
+            },
+            max: match max {
+                "" => i32::MAX,
+                max => max.parse().map_err(invalid)?,
+            },
+        })
+    }
+
+    pub fn contains(&self, protocol_version: i32) -> bool {
+        (self.min..=self.max).contains(&protocol_version)
+    }
+}
+
+impl Display for ProtocolRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.min, self.max) {
+            (min, max) if min == max => write!(f, "{min}"),
+            (i32::MIN, max) => write!(f, "-{max}"),
+            (min, i32::MAX) => write!(f, "{min}-"),
+            (min, max) => write!(f, "{min}-{max}"),
+        }
+    }
+}
+
+/// A route as given on the command line:
+/// "PATTERN=BACKEND[,BACKEND...][;KEY=VALUE...]"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteSpec {
+    pub pattern: HostPattern,
+    /// Only clients with these protocol versions use the route
+    pub protocol: Option<ProtocolRange>,
+    pub backends: Vec<BackendAddr>,
+    pub balance: Option<Strategy>,
+    pub alias_host: Option<String>,
+    pub alias_port: Option<u16>,
+    pub motd_suffix: Option<String>,
+    pub delay: Option<i32>,
+}
+
+impl RouteSpec {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split(';');
+        let (pattern, backends) = parts
+            .next()
+            .and_then(|first| first.split_once('='))
+            .ok_or_else(|| format!("Expected PATTERN=BACKEND, got \"{spec}\""))?;
+        let mut route = RouteSpec {
+            pattern: HostPattern::parse(pattern),
+            protocol: None,
+            backends: backends
+                .split(',')
+                .map(BackendAddr::parse)
+                .collect::<Result<_, _>>()?,
+            balance: None,
+            alias_host: None,
+            alias_port: None,
+            motd_suffix: None,
+            delay: None,
+        };
+        for setting in parts {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Expected KEY=VALUE, got \"{setting}\""))?;
+            let invalid = |_| format!("Invalid value for {key}: \"{value}\"");
+            match key {
+                "protocol" => route.protocol = Some(ProtocolRange::parse(value)?),
+                "balance" => route.balance = Some(Strategy::from_str(value, true)?),
+                "alias-host" => route.alias_host = Some(value.to_owned()),
+                "alias-port" => route.alias_port = Some(value.parse().map_err(invalid)?),
+                "motd" => route.motd_suffix = Some(value.to_owned()),
+                "delay" => route.delay = Some(value.parse().map_err(invalid)?),
+                _ => return Err(format!("Unknown route setting \"{key}\"")),
+            }
+        }
+        Ok(route)
+    }
+}
+
+/// Where and how connections for a hostname get forwarded
+pub struct Route {
+    /// Pattern (and protocol versions) of the route, or "default"
+    pub name: String,
+    pub protocol: Option<ProtocolRange>,
+    pub backends: Arc<Backends>,
+    pub alias_host: Option<String>,
+    pub alias_port: Option<u16>,
+    /// Appended to the MOTD, "{ping}" gets replaced with the own ping in ms
+    pub motd_suffix: String,
+    /// Replaces the delays of both directions
+    pub delay: Option<i32>,
+}
+
+pub struct Router {
+    routes: Vec<(HostPattern, Arc<Route>)>,
+    default: Arc<Route>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<(HostPattern, Arc<Route>)>, default: Arc<Route>) -> Self {
+        Self { routes, default }
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Arc<Route>> {
+        self.routes
+            .iter()
+            .map(|(_, route)| route)
+            .chain(std::iter::once(&self.default))
+    }
+
+    /// Route for the server address and protocol version of a handshake. Exact matches win over
+    /// wildcards, longer wildcards over shorter ones and "*". For the same pattern, routes limited
+    /// to protocol versions win. Falls back to the default route.
+    pub fn route(&self, server_address: &str, protocol_version: i32) -> &Arc<Route> {
+        self.best_route(Some(&normalize_host(server_address)), protocol_version)
+    }
+
+    /// Route for legacy pings which don't tell the hostname (before 1.6). Only routes for any
+    /// hostname ("*") apply, otherwise it's the default route.
+    pub fn route_without_host(&self) -> &Arc<Route> {
+        self.best_route(None, LEGACY_PROTOCOL_VERSION)
+    }
+
+    fn best_route(&self, host: Option<&str>, protocol_version: i32) -> &Arc<Route> {
+        self.routes
+            .iter()
+            .filter(|(pattern, route)| {
+                host.map_or(*pattern == HostPattern::Any, |host| pattern.matches(host))
+                    && route
+                        .protocol
+                        .is_none_or(|protocol| protocol.contains(protocol_version))
+            })
+            // Reversed, so the first of equally specific routes is the max
+            .rev()
+            .max_by_key(|(pattern, route)| (pattern.specificity(), route.protocol.is_some()))
+            .map_or(&self.default, |(_, route)| route)
+    }
+}
+
+/// Lowercase and without anything after a NUL byte (like the Forge marker) or a trailing dot
+fn normalize_host(host: &str) -> String {
+    let host = host.split('\0').next().unwrap_or_default();
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn route(name: &str) -> Arc<Route> {
+        Arc::new(Route {
+            name: name.to_owned(),
+            protocol: None,
+            backends: Arc::new(Backends::new(Vec::new(), Strategy::Failover)),
+            alias_host: None,
+            alias_port: None,
+            motd_suffix: String::new(),
+            delay: None,
+        })
+    }
+
+    #[test]
+    fn parse_route() {
+        let spec =
+            RouteSpec::parse("*.Example.org=10.0.0.2:25566,mirror;alias-host=mc;delay=-1;motd=§a")
+                .unwrap();
+        assert_eq!(
+            spec.pattern,
+            HostPattern::Wildcard(".example.org".to_owned())
+        );
+        assert_eq!(spec.backends.len(), 2);
+        assert_eq!(spec.backends[0].port, Some(25566));
+        assert_eq!(spec.alias_host.as_deref(), Some("mc"));
+        assert_eq!(spec.delay, Some(-1));
+        assert_eq!(spec.motd_suffix.as_deref(), Some("§a"));
+
+        assert!(RouteSpec::parse("example.org").is_err());
+        assert!(RouteSpec::parse("example.org=backend;colour=red").is_err());
+        assert!(RouteSpec::parse("example.org=backend;balance=random").is_err());
+    }
+
+    #[test]
+    fn match_routes() {
+        let router = Router::new(
+            vec![
+                (HostPattern::parse("*.example.org"), route("wildcard")),
+                (HostPattern::parse("*.test.example.org"), route("test")),
+                (
+                    HostPattern::parse("creative.example.org"),
+                    route("creative"),
+                ),
+            ],
+            route("default"),
+        );
+        let name = |host| router.route(host, 767).name.clone();
+        assert_eq!(name("creative.example.org"), "creative");
+        assert_eq!(name("Survival.Example.org."), "wildcard");
+        assert_eq!(name("survival.example.org\0FML3\0"), "wildcard");
+        assert_eq!(name("a.test.example.org"), "test");
+        assert_eq!(name("example.org"), "default");
+        assert_eq!(name("other.net"), "default");
+    }
+
+    #[test]
+    fn match_protocol_versions() {
+        let old = Arc::new(Route {
+            protocol: Some(ProtocolRange::parse("-340").unwrap()),
+            ..Arc::into_inner(route("old")).unwrap()
+        });
+        let router = Router::new(
+            vec![
+                (HostPattern::parse("*"), old.clone()),
+                (HostPattern::parse("*.example.org"), route("wildcard")),
+                (HostPattern::parse("*.example.org"), old),
+            ],
+            route("default"),
+        );
+        let name = |host, version| router.route(host, version).name.clone();
+        assert_eq!(name("other.net", 47), "old");
+        assert_eq!(name("other.net", 767), "default");
+        assert_eq!(name("survival.example.org", 340), "old");
+        assert_eq!(name("survival.example.org", 341), "wildcard");
+        assert_eq!(name("other.net", LEGACY_PROTOCOL_VERSION), "old");
+        assert_eq!(router.route_without_host().name, "old");
+
+        let router = Router::new(
+            vec![(HostPattern::parse("play.example.org"), route("play"))],
+            route("default"),
+        );
+        assert_eq!(router.route_without_host().name, "default");
+    }
+
+    #[test]
+    fn parse_protocol_ranges() {
+        let range = |min, max| Ok(ProtocolRange { min, max });
+        assert_eq!(ProtocolRange::parse("47-340"), range(47, 340));
+        assert_eq!(ProtocolRange::parse("47"), range(47, 47));
+        assert_eq!(ProtocolRange::parse("-340"), range(i32::MIN, 340));
+        assert_eq!(ProtocolRange::parse("-340").unwrap().to_string(), "-340");
+        assert_eq!(ProtocolRange::parse("764-"), range(764, i32::MAX));
+        assert!(ProtocolRange::parse("old").is_err());
+        assert_eq!(ProtocolRange::parse("764-").unwrap().to_string(), "764-");
+    }
+}
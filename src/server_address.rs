@@ -0,0 +1,52 @@
+/// The server address of a handshake. Clients may append NUL separated parts to the host, like
+/// Forge does with "\0FML\0", "\0FML2\0" or "\0FML3\0".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub host: String,
+    /// Everything after the host, split at NUL bytes
+    pub suffix_parts: Vec<String>,
+}
+
+impl ServerAddress {
+    pub fn parse(server_address: &str) -> Self {
+        let mut parts = server_address.split('\0');
+        Self {
+            host: parts.next().unwrap_or_default().to_owned(),
+            suffix_parts: parts.map(str::to_owned).collect(),
+        }
+    }
+
+    /// The address to send to the target instead, with the same suffix parts (unless stripped)
+    pub fn with_host(&self, host: &str, strip_suffix: bool) -> String {
+        if strip_suffix || self.suffix_parts.is_empty() {
+            return host.to_owned();
+        }
+        let mut address = host.to_owned();
+        for part in &self.suffix_parts {
+            address.push('\0');
+            address.push_str(part);
+        }
+        address
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn keep_suffix_parts() {
+        let address = ServerAddress::parse("play.example.org\0FML3\0");
+        assert_eq!(address.host, "play.example.org");
+        assert_eq!(address.suffix_parts, ["FML3", ""]);
+        assert_eq!(
+            address.with_host("mc.internal", false),
+            "mc.internal\0FML3\0"
+        );
+        assert_eq!(address.with_host("mc.internal", true), "mc.internal");
+
+        let address = ServerAddress::parse("play.example.org");
+        assert!(address.suffix_parts.is_empty());
+        assert_eq!(address.with_host("mc.internal", false), "mc.internal");
+    }
+}
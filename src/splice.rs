@@ -0,0 +1,170 @@
+use std::io::Result;
+use std::net::TcpStream;
+
+/// A kernel pipe used to move data between two sockets with splice(2),
+/// without copying it into the proxy.
+#[cfg(target_os = "linux")]
+pub struct KernelPipe {
+    read_end: std::os::fd::OwnedFd,
+    write_end: std::os::fd::OwnedFd,
+    /// Bytes currently in the pipe
+    len: usize,
+    capacity: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl KernelPipe {
+    /// Create a pipe which tries to hold up to `capacity` bytes
+    pub fn new(capacity: usize) -> Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for both ends
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both fds are open and owned by nobody else
+        let (read_end, write_end) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        // Growing the pipe is best effort (limited by /proc/sys/fs/pipe-max-size)
+        let requested = libc::c_int::try_from(capacity).unwrap_or(libc::c_int::MAX);
+        // SAFETY: Plain fcntl call on an fd we own
+        unsafe { libc::fcntl(write_end.as_raw_fd(), libc::F_SETPIPE_SZ, requested) };
+        // SAFETY: Plain fcntl call on an fd we own
+        let capacity = unsafe { libc::fcntl(write_end.as_raw_fd(), libc::F_GETPIPE_SZ) };
+        if capacity <= 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            read_end,
+            write_end,
+            len: 0,
+            capacity: capacity as usize,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Move up to max bytes from the socket into the pipe. Returns 0 on EOF.
+    pub fn fill_from(&mut self, source: &TcpStream, max: usize) -> Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        let max = max.min(self.capacity - self.len);
+        let moved = splice(source.as_raw_fd(), self.write_end.as_raw_fd(), max)?;
+        self.len += moved;
+        Ok(moved)
+    }
+
+    /// Move as much as possible from the pipe into the socket
+    pub fn drain_to(&mut self, sink: &TcpStream) -> Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        let moved = splice(self.read_end.as_raw_fd(), sink.as_raw_fd(), self.len)?;
+        self.len -= moved;
+        Ok(moved)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn splice(fd_in: std::os::fd::RawFd, fd_out: std::os::fd::RawFd, len: usize) -> Result<usize> {
+    // SAFETY: Both fds are open for the duration of the call and no offsets are used
+    let moved = unsafe {
+        libc::splice(
+            fd_in,
+            std::ptr::null_mut(),
+            fd_out,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if moved < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(moved as usize)
+}
+
+/// Stand-in for platforms without splice(2). Can't be created.
+#[cfg(not(target_os = "linux"))]
+pub struct KernelPipe(());
+
+#[cfg(not(target_os = "linux"))]
+impl KernelPipe {
+    pub fn new(_capacity: usize) -> Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "splice is only supported on Linux",
+        ))
+    }
+
+    pub fn len(&self) -> usize {
+        0
+    }
+
+    pub fn capacity(&self) -> usize {
+        0
+    }
+
+    pub fn fill_from(&mut self, _source: &TcpStream, _max: usize) -> Result<usize> {
+        unreachable!("KernelPipe can't be created on this platform")
+    }
+
+    pub fn drain_to(&mut self, _sink: &TcpStream) -> Result<usize> {
+        unreachable!("KernelPipe can't be created on this platform")
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+pub mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn move_through_pipe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (source, _) = listener.accept().unwrap();
+        let sink = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut receiver, _) = listener.accept().unwrap();
+        source.set_nonblocking(true).unwrap();
+
+        let mut pipe = KernelPipe::new(1 << 16).unwrap();
+        assert!(pipe.capacity() >= 4096);
+        sender.write_all(b"Hello through the kernel").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pipe.len() < 24 {
+            assert!(Instant::now() < deadline, "Nothing arrived");
+            match pipe.fill_from(&source, 1024) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => assert!(result.unwrap() > 0),
+            }
+        }
+        assert_eq!(pipe.len(), 24);
+
+        assert_eq!(pipe.drain_to(&sink).unwrap(), 24);
+        assert_eq!(pipe.len(), 0);
+        let mut received = [0u8; 24];
+        receiver.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"Hello through the kernel");
+
+        drop(sender);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(Instant::now() < deadline, "No EOF received");
+            match pipe.fill_from(&source, 1024) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => break assert_eq!(result.unwrap(), 0),
+            }
+        }
+    }
+}
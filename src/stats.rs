@@ -0,0 +1,181 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Traffic of all connections (including already closed ones) since the start
+pub static UPSTREAM_TOTALS: AtomicDirectionStats = AtomicDirectionStats::new();
+pub static DOWNSTREAM_TOTALS: AtomicDirectionStats = AtomicDirectionStats::new();
+
+/// Traffic of one direction of a connection
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirectionStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Successful read syscalls
+    pub reads: u64,
+    /// Successful write syscalls
+    pub writes: u64,
+    /// How often collected data started to get sent
+    pub flushes: u64,
+}
+
+impl DirectionStats {
+    pub fn avg_batch_size(&self) -> u64 {
+        self.bytes_written.checked_div(self.flushes).unwrap_or(0)
+    }
+}
+
+impl Display for DirectionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} in {} reads, {} writes, {} flushes, avg {} per flush",
+            crate::format_bytes(self.bytes_written),
+            self.reads,
+            self.writes,
+            self.flushes,
+            crate::format_bytes(self.avg_batch_size())
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Client -> Target
+    pub upstream: DirectionStats,
+    /// Target -> Client
+    pub downstream: DirectionStats,
+}
+
+impl Display for ConnectionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "client -> target: {}; target -> client: {}",
+            self.upstream, self.downstream
+        )
+    }
+}
+
+/// Same as DirectionStats, but can be shared between threads
+pub struct AtomicDirectionStats {
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub reads: AtomicU64,
+    pub writes: AtomicU64,
+    pub flushes: AtomicU64,
+}
+
+impl AtomicDirectionStats {
+    pub const fn new() -> Self {
+        Self {
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            flushes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> DirectionStats {
+        DirectionStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts into the stats of a connection and the totals (if any) at the same time
+#[derive(Default)]
+pub struct StatsRecorder {
+    pub stats: DirectionStats,
+    totals: Option<&'static AtomicDirectionStats>,
+}
+
+impl StatsRecorder {
+    pub fn new(totals: &'static AtomicDirectionStats) -> Self {
+        Self {
+            stats: DirectionStats::default(),
+            totals: Some(totals),
+        }
+    }
+
+    pub fn record_read(&mut self, bytes: usize) {
+        self.stats.reads += 1;
+        self.stats.bytes_read += bytes as u64;
+        if let Some(totals) = self.totals {
+            totals.reads.fetch_add(1, Ordering::Relaxed);
+            totals.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_write(&mut self, bytes: usize) {
+        self.stats.writes += 1;
+        self.stats.bytes_written += bytes as u64;
+        if let Some(totals) = self.totals {
+            totals.writes.fetch_add(1, Ordering::Relaxed);
+            totals
+                .bytes_written
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_flush(&mut self) {
+        self.stats.flushes += 1;
+        if let Some(totals) = self.totals {
+            totals.flushes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn record_direction() {
+        static TOTALS: AtomicDirectionStats = AtomicDirectionStats::new();
+        let mut first = StatsRecorder::new(&TOTALS);
+        first.record_read(1000);
+        first.record_read(3000);
+        first.record_flush();
+        first.record_write(4000);
+        first.record_flush();
+        first.record_write(2000);
+        first.record_write(1000);
+        assert_eq!(
+            first.stats,
+            DirectionStats {
+                bytes_read: 4000,
+                bytes_written: 7000,
+                reads: 2,
+                writes: 3,
+                flushes: 2,
+            }
+        );
+        assert_eq!(first.stats.avg_batch_size(), 3500);
+        assert_eq!(
+            first.stats.to_string(),
+            "6.8 KiB in 2 reads, 3 writes, 2 flushes, avg 3.4 KiB per flush"
+        );
+
+        // The totals include every connection
+        let mut second = StatsRecorder::new(&TOTALS);
+        second.record_read(10);
+        second.record_flush();
+        second.record_write(10);
+        let totals = TOTALS.snapshot();
+        assert_eq!(totals.bytes_read, 4010);
+        assert_eq!(totals.bytes_written, 7010);
+        assert_eq!((totals.reads, totals.writes, totals.flushes), (3, 4, 3));
+
+        // Nothing flushed yet
+        assert_eq!(DirectionStats::default().avg_batch_size(), 0);
+        assert_eq!(
+            StatsRecorder::default().stats.to_string(),
+            "0 B in 0 reads, 0 writes, 0 flushes, avg 0 B per flush"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use p384::ecdsa::signature::hazmat::PrehashVerifier;
+use p384::ecdsa::{Signature, VerifyingKey};
+use p384::pkcs8::DecodePublicKey;
+use sha2::{Digest, Sha512};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+/// How far the signed timestamp may be off (to allow for clock differences)
+const MAX_CLOCK_DIFFERENCE: Duration = Duration::from_secs(10);
+
+/// Public key of TCPShield to verify the signature with
+#[derive(Clone)]
+pub struct PublicKey(VerifyingKey);
+
+impl PublicKey {
+    /// Read from a file as PEM or just the base64 encoded key
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|err| format!("Read {path}: {err}"))?;
+        let key = if content.contains("-----BEGIN") {
+            VerifyingKey::from_public_key_pem(&content).map_err(|err| err.to_string())
+        } else {
+            base64::engine::general_purpose::STANDARD
+                .decode(content.trim())
+                .map_err(|err| err.to_string())
+                .and_then(|der| {
+                    VerifyingKey::from_public_key_der(&der).map_err(|err| err.to_string())
+                })
+        };
+        key.map(Self)
+            .map_err(|err| format!("Invalid P-384 public key in {path}: {err}"))
+    }
+}
+
+/// Server address of a handshake which went through TCPShield:
+/// "host///ip:port///timestamp///signature"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpShieldAddress {
+    /// Hostname the player used, with the suffix the client appended (like "\0FML3\0")
+    pub server_address: String,
+    pub client_addr: SocketAddr,
+    /// Unix time in seconds
+    timestamp: u64,
+    /// The part which is signed
+    signed: String,
+    signature: String,
+}
+
+impl TcpShieldAddress {
+    /// None if the address is not in the format of TCPShield
+    pub fn parse(server_address: &str) -> Result<Option<Self>> {
+        let parts: Vec<&str> = server_address.splitn(4, "///").collect();
+        let [host, client_addr, timestamp, signature] = parts[..] else {
+            return Ok(None);
+        };
+        // The client suffix is either kept after the host or moved to the end
+        let (signature, suffix) = signature.split_once('\0').unwrap_or((signature, ""));
+        let mut clean_address = host.to_owned();
+        if !suffix.is_empty() {
+            clean_address.push('\0');
+            clean_address.push_str(suffix);
+        }
+        Ok(Some(Self {
+            server_address: clean_address,
+            client_addr: parse_client_addr(client_addr)
+                .with_context(|| format!("Invalid client address \"{client_addr}\""))?,
+            timestamp: timestamp
+                .parse()
+                .with_context(|| format!("Invalid timestamp \"{timestamp}\""))?,
+            signed: format!("{host}///{client_addr}///{timestamp}"),
+            signature: signature.to_owned(),
+        }))
+    }
+
+    /// Check that TCPShield signed the address recently
+    pub fn verify(&self, key: &PublicKey) -> Result<()> {
+        let signature = base64::engine::general_purpose::STANDARD
+            .decode(&self.signature)
+            .context("Signature is not valid base64")?;
+        let signature = Signature::from_der(&signature).context("Invalid signature")?;
+        key.0
+            .verify_prehash(&Sha512::digest(&self.signed), &signature)
+            .context("Signature does not match")?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(self.timestamp) > MAX_CLOCK_DIFFERENCE.as_secs() {
+            bail!(
+                "Signed timestamp is {}s off (replayed handshake?)",
+                now.abs_diff(self.timestamp)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// "IPv4:port", "[IPv6]:port" or "IPv6:port"
+fn parse_client_addr(client_addr: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = client_addr.parse() {
+        return Ok(addr);
+    }
+    let (ip, port) = client_addr.rsplit_once(':').context("No port")?;
+    Ok(SocketAddr::new(ip.parse::<IpAddr>()?, port.parse()?))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use p384::ecdsa::signature::hazmat::PrehashSigner;
+    use p384::ecdsa::SigningKey;
+
+    #[test]
+    fn parse_and_verify() {
+        let signing_key = SigningKey::from_slice(&[7u8; 48]).unwrap();
+        let key = PublicKey(*signing_key.verifying_key());
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let sign = |signed: &str| {
+            let signature: Signature = signing_key.sign_prehash(&Sha512::digest(signed)).unwrap();
+            base64::engine::general_purpose::STANDARD.encode(signature.to_der())
+        };
+
+        let signed = format!("play.example.org///203.0.113.7:51234///{now}");
+        let address = format!("{signed}///{}\0FML3\0", sign(&signed));
+        let parsed = TcpShieldAddress::parse(&address).unwrap().unwrap();
+        assert_eq!(parsed.server_address, "play.example.org\0FML3\0");
+        assert_eq!(parsed.client_addr, "203.0.113.7:51234".parse().unwrap());
+        parsed.verify(&key).unwrap();
+
+        let forged = address.replace("203.0.113.7", "203.0.113.8");
+        let forged = TcpShieldAddress::parse(&forged).unwrap().unwrap();
+        assert!(forged.verify(&key).is_err());
+
+        let old = format!("play.example.org///[2001:db8::7]:51234///{}", now - 60);
+        let old = TcpShieldAddress::parse(&format!("{old}///{}", sign(&old)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(old.client_addr, "[2001:db8::7]:51234".parse().unwrap());
+        assert!(old.verify(&key).is_err());
+
+        assert_eq!(TcpShieldAddress::parse("play.example.org").unwrap(), None);
+        assert!(TcpShieldAddress::parse("a///b///c///d").is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::Display;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Something took too long. Attached to errors so the logged reason says which phase timed out.
+#[derive(Debug, Clone, Copy)]
+pub enum Timeout {
+    /// Client didn't finish handshake, status/ping or login start in time
+    PreLogin(Duration),
+    /// Target didn't accept the connection in time
+    Connect(Duration),
+    /// Target didn't answer the status query in time
+    TargetStatus(Duration),
+    /// Target didn't send its first login packet in time
+    TargetLogin(Duration),
+}
+
+impl Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Timeout::PreLogin(timeout) => write!(
+                f,
+                "Client did not complete the handshake within {}s",
+                timeout.as_secs()
+            ),
+            Timeout::Connect(timeout) => write!(
+                f,
+                "Target did not accept the connection within {}s",
+                timeout.as_secs()
+            ),
+            Timeout::TargetStatus(timeout) => write!(
+                f,
+                "Target did not respond to the status query within {}s",
+                timeout.as_secs()
+            ),
+            Timeout::TargetLogin(timeout) => write!(
+                f,
+                "Target did not respond to the login within {}s",
+                timeout.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Reads and writes on a blocking socket which fail once the deadline passed, no matter how
+/// slowly the other side trickles in data (so a single read timeout is not enough).
+pub struct DeadlineStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+    timeout: Timeout,
+}
+
+impl<'a> DeadlineStream<'a> {
+    pub fn new(stream: &'a TcpStream, deadline: Instant, timeout: Timeout) -> Self {
+        Self {
+            stream,
+            deadline,
+            timeout,
+        }
+    }
+
+    /// Give the peer until a new deadline, e.g. after waiting for something else in between
+    pub fn reset_deadline(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+    }
+
+    /// Look at the next bytes without consuming them
+    pub fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.peek(buf).map_err(|err| self.map_err(err))
+    }
+
+    fn remaining(&self) -> std::io::Result<Duration> {
+        self.deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| self.timed_out())
+    }
+
+    fn timed_out(&self) -> std::io::Error {
+        std::io::Error::new(ErrorKind::TimedOut, self.timeout)
+    }
+
+    /// Socket timeouts surface as WouldBlock (or TimedOut, depending on the platform)
+    fn map_err(&self, err: std::io::Error) -> std::io::Error {
+        match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => self.timed_out(),
+            _ => err,
+        }
+    }
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf).map_err(|err| self.map_err(err))
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf).map_err(|err| self.map_err(err))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Use the timeout (if any caused the error) as the message that gets logged
+pub fn explain(err: anyhow::Error) -> anyhow::Error {
+    let timeout = err.chain().find_map(|cause| {
+        cause.downcast_ref::<Timeout>().copied().or_else(|| {
+            cause
+                .downcast_ref::<std::io::Error>()?
+                .get_ref()?
+                .downcast_ref::<Timeout>()
+                .copied()
+        })
+    });
+    match timeout {
+        Some(timeout) => err.context(timeout),
+        None => err,
+    }
+}